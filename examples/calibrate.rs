@@ -0,0 +1,41 @@
+//! Find Argon2 costs that make a hash take a while on this machine, and print
+//! them as config. Run it in release mode on the machine serving logins:
+//! ```sh
+//! cargo run --release --example calibrate -- [target ms = 500] [memory KiB = 12288] [parallelism = 1]
+//! ```
+use std::time::Duration;
+
+use rust_authentication::{config::HashingConfig, password::calibrate};
+
+fn main() {
+    let defaults = HashingConfig::default();
+    let mut args = std::env::args().skip(1).map(|arg| arg.parse::<u32>());
+    let mut next = |default: u32| match args.next() {
+        Some(Ok(value)) => value,
+        Some(Err(e)) => {
+            eprintln!("Arguments have to be numbers: {e}");
+            std::process::exit(2);
+        },
+        None => default,
+    };
+    let target = Duration::from_millis(next(500).into());
+    let memory_cost = next(defaults.memory_cost);
+    let parallelism = next(defaults.parallelism);
+
+    if cfg!(debug_assertions) {
+        eprintln!("Not built in release mode, hashes will be a lot slower than when serving logins.");
+    }
+    match calibrate(target, memory_cost, parallelism) {
+        Ok(calibration) => {
+            eprintln!("A hash takes {:?}", calibration.took);
+            println!("[default.auth.hashing]");
+            println!("memory_cost = {}", calibration.hashing.memory_cost);
+            println!("iterations = {}", calibration.hashing.iterations);
+            println!("parallelism = {}", calibration.hashing.parallelism);
+        },
+        Err(e) => {
+            eprintln!("Argon2 won't take those costs: {e}");
+            std::process::exit(1);
+        },
+    }
+}
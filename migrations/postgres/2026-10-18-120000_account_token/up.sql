@@ -0,0 +1,9 @@
+-- Single-use tokens that get emailed out, ex: for resetting a password.
+-- Only a hash of the token is kept.
+CREATE TABLE account_token (
+    id              SERIAL PRIMARY KEY,
+    account_id      INTEGER NOT NULL REFERENCES account (id) ON DELETE CASCADE,
+    purpose         VARCHAR NOT NULL,
+    token_hash      BYTEA UNIQUE NOT NULL,
+    expires_at      BIGINT NOT NULL
+);
@@ -0,0 +1,4 @@
+-- Accounts made from now on need to verify their email.
+-- The ones that already exist predate that, so they are taken as verified.
+ALTER TABLE account ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT FALSE;
+UPDATE account SET email_verified = TRUE;
@@ -0,0 +1,2 @@
+-- Deleted accounts are kept around for a grace period before being purged.
+ALTER TABLE account ADD COLUMN deleted_at BIGINT;
@@ -0,0 +1,8 @@
+-- When and from where each account logged in.
+CREATE TABLE login_history (
+    id              SERIAL PRIMARY KEY,
+    account_id      INTEGER NOT NULL REFERENCES account (id) ON DELETE CASCADE,
+    at              BIGINT NOT NULL,
+    ip              VARCHAR,
+    user_agent      VARCHAR
+);
@@ -0,0 +1,4 @@
+-- Whether the account can log in: 'active', 'locked', 'disabled' or 'pending_approval'.
+-- Separately, an account can be set to expire at a point in time.
+ALTER TABLE account ADD COLUMN status VARCHAR NOT NULL DEFAULT 'active';
+ALTER TABLE account ADD COLUMN expires_at BIGINT;
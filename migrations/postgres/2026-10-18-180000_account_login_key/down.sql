@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX account_login_key;
+ALTER TABLE account DROP COLUMN login_key;
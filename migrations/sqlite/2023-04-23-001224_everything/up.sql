@@ -0,0 +1,19 @@
+-- Before there were migrations the table was made by `src/new.sql`, which called the
+-- email `username`. Those databases are rebuilt into the shape below, keeping their ids.
+-- SQLite can't do that only if the table exists, so a new database starts out in the
+-- old shape too, and gets rebuilt while still empty.
+CREATE TABLE IF NOT EXISTS account (
+    id              INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    username        VARCHAR UNIQUE NOT NULL,
+    password_hash   BYTEA NOT NULL
+);
+
+CREATE TABLE account_upgraded (
+    id              INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    email           TEXT UNIQUE NOT NULL,
+    password_hash   BLOB NOT NULL
+);
+INSERT INTO account_upgraded (id, email, password_hash)
+    SELECT id, username, password_hash FROM account;
+DROP TABLE account;
+ALTER TABLE account_upgraded RENAME TO account;
@@ -0,0 +1,9 @@
+-- Single-use tokens that get emailed out, ex: for resetting a password.
+-- Only a hash of the token is kept.
+CREATE TABLE IF NOT EXISTS account_token (
+    id              INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    account_id      INTEGER NOT NULL REFERENCES account (id) ON DELETE CASCADE,
+    purpose         TEXT NOT NULL,
+    token_hash      BLOB UNIQUE NOT NULL,
+    expires_at      BIGINT NOT NULL
+);
@@ -0,0 +1,2 @@
+-- Whatever a token needs to remember, ex: the address an email change is going to.
+ALTER TABLE account_token ADD COLUMN data TEXT;
@@ -0,0 +1,8 @@
+-- When and from where each account logged in.
+CREATE TABLE IF NOT EXISTS login_history (
+    id              INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    account_id      INTEGER NOT NULL REFERENCES account (id) ON DELETE CASCADE,
+    at              BIGINT NOT NULL,
+    ip              TEXT,
+    user_agent      TEXT
+);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE account DROP COLUMN expires_at;
+ALTER TABLE account DROP COLUMN status;
@@ -0,0 +1,8 @@
+-- The normalised form of the email, see `identifier::normalize`. Logins are matched
+-- and kept unique on this instead, so the email can stay as they typed it.
+--
+-- Existing accounts are filled in at startup (`AccountDb::normalize_logins`), as the
+-- normalisation can't be done in SQL. Accounts that would collide are reported, only the
+-- oldest gets the key, the others can still log in with their exact email until sorted out.
+ALTER TABLE account ADD COLUMN login_key TEXT;
+CREATE UNIQUE INDEX account_login_key ON account (login_key);
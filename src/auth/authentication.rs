@@ -3,7 +3,11 @@ use std::str::FromStr;
 use rocket::{request::{FromRequest, self, Outcome}, Request, tokio::sync::RwLock, http::{Status, Cookie}};
 use serde::Serialize;
 use tracing::*;
-use super::keyring::{Keyring, KeyStorage};
+use super::keyring::{ClientInfo, Keyring, KeyStorage};
+use crate::config::AuthConfig;
+use crate::db::AccountStoreError;
+use crate::password::Hasher;
+use crate::store::{LoginRecord, ManagedStore};
 
 pub const SESSION_COOKIE_ID: &str = "session-id";
 pub const USERNAME_HEADER_ID: &str = "email";
@@ -22,9 +26,9 @@ impl From<uuid::Uuid> for Uuid {
     }
 }
 
-impl ToString for Uuid {
-    fn to_string(&self) -> String {
-        self.uuid.to_string()
+impl std::fmt::Display for Uuid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.uuid.fmt(f)
     }
 }
 
@@ -34,23 +38,35 @@ impl ToString for Uuid {
 /// a valid session id, if that doesn't exist it will check the headers for a email /
 /// password combo, and try to log them in that way. If both of these fail, it will
 /// throw an error and the request will not continue.
+#[derive(Clone)]
 pub struct Session {
     pub uuid: Uuid,
+    /// The id of the account that is logged in.
+    pub account_id: i32,
     pub email: String,
+    /// When they logged in, in seconds since the unix epoch.
+    pub created_at: i64,
+    /// Where they logged in from.
+    pub client: ClientInfo,
 }
 
 impl Session {
 
     /// This will return [`None`] if the uuid isn't registered in the keyring.
     async fn new_from_keyring<M>(uuid: Uuid, keyring: &RwLock<Keyring<M>>) -> Option<Self> where M: KeyStorage + ?Sized {    
-        if let Some(email) = keyring.read().await.get_username_by_uuid(&uuid) {
-            return Some( Self { uuid, email } );
+        if let Some(data) = keyring.read().await.get_by_uuid(&uuid) {
+            return Some( Self { uuid, account_id: data.account_id, email: data.email, created_at: data.created_at, client: data.client } );
         }
         None
     }
     
-    pub fn new(uuid: Uuid, email: String) -> Self {
-        Self { uuid, email }
+    pub fn new(uuid: Uuid, account_id: i32, email: String, created_at: i64, client: ClientInfo) -> Self {
+        Self { uuid, account_id, email, created_at, client }
+    }
+
+    /// Did they log in within the last `window` seconds?
+    pub fn is_fresh(&self, window: u64) -> bool {
+        crate::now() - self.created_at <= window as i64
     }
 }
 
@@ -62,6 +78,90 @@ impl Serialize for Session {
     }
 }
 
+/// The result of authenticating a request, kept in the request's local cache so
+/// every guard that needs a [`Session`] during one request shares a single lookup.
+struct CachedSession(Result<Session, LoginError>);
+
+impl Session {
+    /// The actual authentication, see [`Session::from_request`].
+    /// Only ever run once per request, its result is cached by the caller.
+    async fn authenticate(request: &Request<'_>) -> Result<Session, LoginError> {
+
+        fn set_cookie(session: &Session, jar: &rocket::http::CookieJar) {
+            jar.add_private(Cookie::new(SESSION_COOKIE_ID, session.uuid.to_string()));
+        }
+
+        // Get the keyring and accounts from rocket
+        let state = request.rocket().state::<crate::ManagedState>()
+            .zip(request.rocket().state::<ManagedStore>());
+        let Some((keyring, accounts)) = state else {
+            return Err(LoginError::DatabaseError);
+        };
+        // Without the fairing's config, nothing extra is required of them.
+        let require_verified = request.rocket().state::<AuthConfig>()
+            .is_some_and(|config| config.require_verified);
+        let hasher = request.rocket().state::<Hasher>().cloned().unwrap_or_default();
+
+        // Check the user's cookies for a session id 
+        if let Some(session_cookie) = request.cookies().get_private(SESSION_COOKIE_ID) {
+            // Extract the cookie into a uuid
+            if let Ok(id) = uuid::Uuid::from_str(session_cookie.value()) {
+                // Try to get a new session object for the request.
+                // If the session id given by the user is invalid this will return `None` and
+                // thus fall down and try to authenticate the user via other methods.
+                if let Some(session) = Session::new_from_keyring(Uuid::from(id), keyring).await {
+                    trace!("Authenticating via cookie");
+
+                    // The account might have been locked (or the like) since they logged in.
+                    let user = accounts.find_by_id(session.account_id)
+                        .await
+                        .map_err(LoginError::Store)?
+                        .ok_or(LoginError::NoAccount)?;
+                    user.check_standing(crate::now())?;
+                    if require_verified && !user.verified {
+                        return Err(LoginError::Unverified);
+                    }
+
+                    // Add the session to their cookie jar.
+                    set_cookie(&session, request.cookies());
+                    // authenticate user
+                    return Ok(session);
+                }
+            }    
+        };
+        // Something above, has at this point, gone wrong.
+
+        // This allows the user to "login" on any arbitrary http request that requires
+        // authentication. Don't really see that as a problem but it seems odd.
+        match request.headers().get_one(USERNAME_HEADER_ID) {
+            Some(username) => {
+                match request.headers().get_one(PASSWORD_HEADER_ID) {
+                    Some(password) => {
+                        let user = Keyring::<dyn KeyStorage>::check_credentials(accounts.as_ref(), &hasher, username, password).await?;
+                        if require_verified && !user.verified {
+                            return Err(LoginError::Unverified);
+                        }
+                        let client = ClientInfo::from_request(request);
+                        let login = LoginRecord { at: crate::now(), ip: client.ip.clone(), user_agent: client.user_agent.clone() };
+                        let id = Keyring::start_session(keyring, &user, client).await;
+                        // Not being able to keep the history shouldn't stop them from logging in.
+                        match accounts.record_login(user.id, login).await {
+                            Ok(()) | Err(AccountStoreError::Unsupported(_)) => {},
+                            Err(e) => error!("Failed to record login for account {}: {}", user.id, e),
+                        }
+                        trace!("Authenticating via user/pass combo");
+                        set_cookie(&id, request.cookies());
+                        // using username / password combo.
+                        Ok(id)
+                    },
+                    None => Err(LoginError::WrongPassword)
+                }
+            },
+            None => Err(LoginError::NoAccount)
+        }
+    }
+}
+
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for Session {
     type Error = LoginError;
@@ -71,83 +171,69 @@ impl<'r> FromRequest<'r> for Session {
     /// it will fall back to trying to read the `USERNAME_HEADER_ID` and `PASSWORD_HEADER_ID`
     /// (as each defined as const values) from the user's header, if these exist it will
     /// try to authenticate them that way.
+    ///
+    /// This only happens once per request, any later `Session` guards on the same request
+    /// get a copy of the first result. (So a header login doesn't mint multiple sessions.)
+    ///
+    /// Accounts that can't log in (ex: locked or expired) are turned away, even if they
+    /// already have a session. If `require_verified` is set in the [`AuthConfig`], accounts
+    /// that haven't verified their email are turned away with [`LoginError::Unverified`].
     /// # Return
     /// If the function is successful in authenticating the user it will return their 
     /// session id.
     /// If the function is unsuccessful it will return an error.
     async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
-        
-        fn set_cookie(session: &Session, jar: &rocket::http::CookieJar) {
-            jar.add_private(Cookie::new(SESSION_COOKIE_ID, session.uuid.to_string()));
-        }
+        let cached = request.local_cache_async(async {
+            CachedSession(Session::authenticate(request).await)
+        }).await;
 
-        // Get the keyring from rocket
-        if let Some(keyring) = request.rocket().state::<crate::ManagedState>() {
-            
-            // Check the user's cookies for a session id 
-            if let Some(session_cookie) = request.cookies().get_private(SESSION_COOKIE_ID) {
-                // Extract the cookie into a uuid
-                if let Ok(id) = uuid::Uuid::from_str(session_cookie.value()) {
-                    // Try to get a new session object for the request.
-                    // If the session id given by the user is invalid this will return `None` and
-                    // thus fall down and try to authenticate the user via other methods.
-                    if let Some(session) = Session::new_from_keyring(Uuid::from(id), keyring).await {
-                        trace!("Authenticating via cookie");
-                        
-                        // Add the session to their cookie jar.
-                        set_cookie(&session, request.cookies());
-                        // authenticate user
-                        return Outcome::Success( session );
-                    }
-                }    
-            };
-            // Something above, has at this point, gone wrong.
-
-            // This allows the user to "login" on any arbitrary http request that requires
-            // authentication. Don't really see that as a problem but it seems odd.
-            match request.headers().get_one(USERNAME_HEADER_ID) {
-                Some(username) => {
-                    match request.headers().get_one(PASSWORD_HEADER_ID) {
-                        Some(password) => {
-                            match keyring.write().await.login(username, password) {
-                                Some(id) => {
-                                    trace!("Authenticating via user/pass combo");
-                                    set_cookie(&id, request.cookies());
-                                    // using username / password combo.
-                                    Outcome::Success( id )
-                                },
-                                None => LoginError::DatabaseError.fail()
-                            }
-                        },
-                        None => LoginError::WrongPassword.fail()
-                    }
-                },
-                None => LoginError::NoAccount.fail()
-            }
-        } else {
-            // Logging in with a session id and email/password combo have both failed        
-            LoginError::DatabaseError.fail() 
+        match &cached.0 {
+            Ok(session) => Outcome::Success(session.clone()),
+            Err(e) => e.clone().fail(),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum LoginError {
+    /// The keyring or account database isn't being managed by Rocket.
     DatabaseError,
+    /// The account database failed while looking the user up.
+    Store(AccountStoreError),
     NoAccount,
     WrongPassword,
+    /// They haven't verified their email, and `require_verified` is set.
+    Unverified,
+    /// The account is locked for now.
+    Locked,
+    /// The account was disabled.
+    Disabled,
+    /// The account hasn't been approved yet.
+    PendingApproval,
+    /// The account's expiry date has passed.
+    Expired,
 }
 
 impl LoginError {
-    /// Quickly fail an Outcome with pre-set Statuses for each.
-    /// Will never return a session.
-    fn fail(self) -> Outcome<Session, Self> {
-        // Set your favorite Statuses here.
-        let status = match self {
+    /// Set your favorite Statuses here.
+    pub fn status(&self) -> Status {
+        match self {
             LoginError::DatabaseError   => Status::InternalServerError,
+            LoginError::Store(AccountStoreError::Unavailable(_)) => Status::ServiceUnavailable,
+            LoginError::Store(_)        => Status::InternalServerError,
             LoginError::NoAccount       => Status::Unauthorized,
             LoginError::WrongPassword   => Status::Unauthorized,
-        };
-        Outcome::Error((status, self))
+            LoginError::Unverified      => Status::Forbidden,
+            LoginError::Locked          => Status::Locked,
+            LoginError::Disabled        => Status::Forbidden,
+            LoginError::PendingApproval => Status::Forbidden,
+            LoginError::Expired         => Status::Forbidden,
+        }
+    }
+
+    /// Quickly fail an Outcome with pre-set Statuses for each.
+    /// Will never return a session.
+    fn fail(self) -> Outcome<Session, Self> {
+        Outcome::Error((self.status(), self))
     }
 }
@@ -1,11 +1,13 @@
-use super::authentication::{Session, Uuid};
-use crate::db::{redis_connect, Account};
-use argon2::{
-    password_hash::SaltString,
-    password_hash::{rand_core::OsRng, PasswordHashString},
-    Algorithm, Argon2, Params, PasswordHasher, PasswordVerifier, Version,
-};
+use super::authentication::{LoginError, Session, Uuid};
+use crate::store::{UserRecord, UserStore};
+use crate::password::Hasher;
+use argon2::password_hash::{self, PasswordHashString};
+#[cfg(feature = "redis")]
 use redis::Commands;
+#[cfg(feature = "redis")]
+use rocket::serde::json;
+use rocket::tokio::{sync::RwLock, task::spawn_blocking};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use tracing::*;
 
@@ -19,34 +21,209 @@ pub trait KeyStorage {
     fn save(&mut self, session: &Session);
     /// Discard a session
     fn discard(&mut self, session: &Session);
+    /// Discard every session belonging to an account, other than `keep`
+    fn discard_account(&mut self, account_id: i32, keep: Option<&Uuid>);
+    /// Change the email kept with every session belonging to an account
+    fn update_email(&mut self, account_id: i32, email: &str);
+    /// Every session belonging to an account
+    fn sessions_of(&self, account_id: i32) -> Vec<(Uuid, SessionData)>;
     /// Get the value by they key
-    fn value_by_key(&self, uuid: &Uuid) -> Option<String>;
+    fn value_by_key(&self, uuid: &Uuid) -> Option<SessionData>;
+    /// Can the storage currently be reached?
+    fn is_available(&self) -> bool {
+        true
+    }
+}
+
+/// What gets stored alongside each session id.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SessionData {
+    pub account_id: i32,
+    pub email: String,
+    /// When they logged in, in seconds since the unix epoch.
+    pub created_at: i64,
+    /// Sessions saved before this was kept won't have it.
+    #[serde(default)]
+    pub client: ClientInfo,
+}
+
+impl From<&Session> for SessionData {
+    fn from(session: &Session) -> Self {
+        Self {
+            account_id: session.account_id,
+            email: session.email.to_owned(),
+            created_at: session.created_at,
+            client: session.client.clone(),
+        }
+    }
+}
+
+/// Where a login came from, as far as the request could tell.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientInfo {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl ClientInfo {
+    pub fn from_request(request: &rocket::Request<'_>) -> Self {
+        Self {
+            ip: request.client_ip().map(|ip| ip.to_string()),
+            user_agent: request.headers().get_one("User-Agent").map(str::to_owned),
+        }
+    }
+}
+
+/// What the keyring actually holds, so the backend can be picked at runtime.
+pub trait SessionStore = KeyStorage + Send + Sync;
+
+/// A connection to Redis, alongside the client that made it so that
+/// reads (which only get `&self`) can open their own connection.
+#[cfg(feature = "redis")]
+pub struct RedisStorage {
+    client: redis::Client,
+    connection: redis::Connection,
+}
+
+#[cfg(feature = "redis")]
+impl RedisStorage {
+    pub fn new(client: redis::Client) -> Result<Self, redis::RedisError> {
+        let connection = client.get_connection()?;
+        Ok(Self { client, connection })
+    }
+
+    /// The set holding every session id of an account.
+    fn account_key(account_id: i32) -> String {
+        format!("account:{account_id}:sessions")
+    }
 }
 
-impl KeyStorage for redis::Connection {
+#[cfg(feature = "redis")]
+impl KeyStorage for RedisStorage {
     fn save(&mut self, session: &Session) {
-        if let Err(e) = self.set::<String, String, ()>(session.uuid.to_string(), session.email.to_owned()) {
+        let data = match json::to_string(&SessionData::from(session)) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Failed to serialize session: {}", e);
+                return;
+            }
+        };
+        let saved = redis::pipe()
+            .set(session.uuid.to_string(), data)
+            .sadd(Self::account_key(session.account_id), session.uuid.to_string())
+            .query::<()>(&mut self.connection);
+        if let Err(e) = saved {
             error!("Error while saving session to redis: {}", e);
             warn!("Currently doesn't have a way to stop the login process from here... User will not be logged in even though the request will complete.");
         }
     }
 
     fn discard(&mut self, session: &Session) {
-        if let Err(e) = self.del::<String, ()>(session.uuid.to_string()) {
+        let deleted = redis::pipe()
+            .del(session.uuid.to_string())
+            .srem(Self::account_key(session.account_id), session.uuid.to_string())
+            .query::<()>(&mut self.connection);
+        if let Err(e) = deleted {
             error!("Error while deleting session. {}", e);
             warn!("Session: '{}' for '{}' might be orphaned now...", session.uuid.to_string(), session.email);
         }
     }
 
-    fn value_by_key(&self, uuid: &Uuid) -> Option<String> {
+    fn discard_account(&mut self, account_id: i32, keep: Option<&Uuid>) {
+        let key = Self::account_key(account_id);
+        let sessions: Vec<String> = match self.connection.smembers(&key) {
+            Ok(sessions) => sessions,
+            Err(e) => {
+                error!("Failed to list sessions for account {}. {}", account_id, e);
+                return;
+            }
+        };
+
+        let keep = keep.map(|k| k.to_string());
+        let mut pipe = redis::pipe();
+        for session in sessions.iter().filter(|s| Some(*s) != keep.as_ref()) {
+            pipe.del(session).srem(&key, session);
+        }
+        if let Err(e) = pipe.query::<()>(&mut self.connection) {
+            error!("Error while deleting sessions for account {}. {}", account_id, e);
+        }
+    }
+
+    fn update_email(&mut self, account_id: i32, email: &str) {
+        let sessions: Vec<String> = match self.connection.smembers(Self::account_key(account_id)) {
+            Ok(sessions) => sessions,
+            Err(e) => {
+                error!("Failed to list sessions for account {}. {}", account_id, e);
+                return;
+            }
+        };
+
+        for session in sessions {
+            let data = match self.connection.get::<_, Option<String>>(&session) {
+                Ok(Some(data)) => data,
+                // Expired or logged out since being listed.
+                Ok(None) => continue,
+                Err(e) => {
+                    error!("Failed to get '{session}' from redis. {:?}", e);
+                    continue;
+                }
+            };
+            let updated = json::from_str::<SessionData>(&data)
+                .map(|data| SessionData { email: email.to_owned(), ..data })
+                .and_then(|data| json::to_string(&data));
+            let saved = match updated {
+                Ok(data) => self.connection.set::<_, _, ()>(&session, data),
+                Err(e) => {
+                    error!("Session '{session}' in redis is unreadable. {:?}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = saved {
+                error!("Failed to update the email of session '{session}'. {}", e);
+            }
+        }
+    }
+
+    fn sessions_of(&self, account_id: i32) -> Vec<(Uuid, SessionData)> {
+        let mut red = match self.client.get_connection() {
+            Ok(red) => red,
+            Err(e) => {
+                error!("Failed to connect to redis: {:?}", e);
+                return Vec::new();
+            },
+        };
+        let sessions: Vec<String> = match red.smembers(Self::account_key(account_id)) {
+            Ok(sessions) => sessions,
+            Err(e) => {
+                error!("Failed to list sessions for account {}. {}", account_id, e);
+                return Vec::new();
+            }
+        };
+
+        sessions
+            .iter()
+            .filter_map(|session| uuid::Uuid::parse_str(session).ok())
+            .map(Uuid::from)
+            .filter_map(|uuid| self.value_by_key(&uuid).map(|data| (uuid, data)))
+            .collect()
+    }
+
+    fn value_by_key(&self, uuid: &Uuid) -> Option<SessionData> {
         // There is no reason that a get command needs it's self as mutable...
         // So Ill just get a new connection lol
 
         let uuid = uuid.to_string();
 
-        match redis_connect() {
-            Ok(mut red) => match red.get(&uuid) {
-                Ok(e) => Some(e),
+        match self.client.get_connection() {
+            Ok(mut red) => match red.get::<_, Option<String>>(&uuid) {
+                Ok(Some(data)) => match json::from_str(&data) {
+                    Ok(data) => Some(data),
+                    Err(e) => {
+                        error!("Session '{uuid}' in redis is unreadable. {:?}", e);
+                        None
+                    }
+                },
+                Ok(None) => None,
                 Err(e) => {
                     error!("Failed to get '{uuid}' from redis. {:?}", e);
                     None
@@ -58,18 +235,42 @@ impl KeyStorage for redis::Connection {
             },
         }
     }
+
+    fn is_available(&self) -> bool {
+        match self.client.get_connection() {
+            Ok(mut red) => redis::cmd("PING").query::<String>(&mut red).is_ok(),
+            Err(_) => false,
+        }
+    }
 }
 
-impl KeyStorage for HashMap<Uuid, String> {
+impl KeyStorage for HashMap<Uuid, SessionData> {
     fn save(&mut self, session: &Session) {
-        self.insert(session.uuid, session.email.to_owned());
+        self.insert(session.uuid, SessionData::from(session));
     }
 
     fn discard(&mut self, session: &Session) {
         self.remove(&session.uuid);
     }
 
-    fn value_by_key(&self, uuid: &Uuid) -> Option<String> {
+    fn discard_account(&mut self, account_id: i32, keep: Option<&Uuid>) {
+        self.retain(|uuid, data| data.account_id != account_id || Some(uuid) == keep);
+    }
+
+    fn update_email(&mut self, account_id: i32, email: &str) {
+        for data in self.values_mut().filter(|data| data.account_id == account_id) {
+            data.email = email.to_owned();
+        }
+    }
+
+    fn sessions_of(&self, account_id: i32) -> Vec<(Uuid, SessionData)> {
+        self.iter()
+            .filter(|(_, data)| data.account_id == account_id)
+            .map(|(uuid, data)| (*uuid, data.clone()))
+            .collect()
+    }
+
+    fn value_by_key(&self, uuid: &Uuid) -> Option<SessionData> {
         self.get(uuid).cloned()
     }
 }
@@ -87,50 +288,97 @@ where
     M: KeyStorage + ?Sized,
 {
     /// A centralized way to hash passwords
-    /// for the web api.
-    pub fn hash_password(password: &str) -> PasswordHashString {
-        // as defined in: https://cheatsheetseries.owasp.org/cheatsheets/Password_Storage_Cheat_Sheet.html#argon2id
-        let params = match Params::new(3 << 12, 3, Params::DEFAULT_P_COST, None) {
-            Ok(r) => r,
-            Err(_) => panic!("Hard-coded values a wrong?\nFailed to create argon2 object."),
-        };
-        let argon = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+    /// for the web api. See [`Hasher::hash`].
+    pub fn hash_password(password: &str, hasher: &Hasher) -> Result<PasswordHashString, password_hash::Error> {
+        hasher.hash(password)
+    }
 
-        let salt = SaltString::generate(OsRng);
-        let hash = match argon.hash_password(password.as_bytes(), &salt) {
-            Ok(k) => k,
-            Err(_) => panic!("Crate-defined defaults threw an error whilst creating argon2 hash."),
-        };
-        hash.serialize()
+    /// Check a password against the hash that was stored for it. See [`Hasher::verify`].
+    pub fn verify_password(password: &str, stored_hash: &str, hasher: &Hasher) -> bool {
+        hasher.verify(password, stored_hash)
     }
 
     /// # Login
     /// Will try to log the user designated by the given username and password.
     /// If this attempt it successful it will return them a new [`Session`].
-    pub fn login(&mut self, username: &str, password: &str) -> Option<Session> {
+    pub async fn login(keyring: &RwLock<Self>, accounts: &dyn UserStore, hasher: &Hasher, username: &str, password: &str) -> Result<Session, LoginError> {
+        let user = Self::check_credentials(accounts, hasher, username, password).await?;
+        Ok(Self::start_session(keyring, &user, ClientInfo::default()).await)
+    }
+
+    /// Find the account and check the password, without logging them in.
+    /// Fails if the account can't log in right now, see [`UserRecord::check_standing`].
+    ///
+    /// If the password was hashed with outdated costs, another pepper or another
+    /// algorithm (like the hashes of imported accounts), it gets rehashed the way
+    /// `hasher` hashes now and stored, see [`Hasher::needs_rehash`].
+    pub async fn check_credentials(accounts: &dyn UserStore, hasher: &Hasher, username: &str, password: &str) -> Result<UserRecord, LoginError> {
         // search the db for the account under that username.
-        if let Some(stored_hash) = Account::get_account_hash(username) {
-            // then see if the password hashes match.
-            let argon = Argon2::default();
-            if argon
-                .verify_password(password.as_bytes(), &stored_hash.password_hash())
-                .is_ok()
-            {
-                // generate them a user id
-                let user_id = Uuid::from(uuid::Uuid::new_v4());
-                let session = Session::new(user_id, username.to_owned());
-                self.ring.save(&session);
-                return Some(session);
+        let user = accounts.find_by_login(username)
+            .await
+            .map_err(LoginError::Store)?
+            .ok_or(LoginError::NoAccount)?;
+        let stored_hash = user.password_hash.clone();
+
+        // then see if the password hashes match.
+        let given = password.to_owned();
+        let verifier = hasher.clone();
+        let matches = spawn_blocking(move || verifier.verify(&given, &stored_hash))
+            .await
+            .unwrap_or(false);
+
+        if !matches {
+            return Err(LoginError::WrongPassword);
+        }
+        // Only tell them why they can't log in once they've shown it's their account.
+        user.check_standing(crate::now())?;
+
+        let mut user = user;
+        if hasher.needs_rehash(&user.password_hash) {
+            let password = password.to_owned();
+            let hasher = hasher.clone();
+            match spawn_blocking(move || hasher.hash(&password)).await {
+                Ok(Ok(hash)) => match accounts.update_credentials(user.id, hash.as_str()).await {
+                    Ok(()) => user.password_hash = hash.to_string(),
+                    // they still get in, it'll be tried again next time
+                    Err(e) => error!("Failed to store the rehashed password of account {}: {}", user.id, e),
+                },
+                Ok(Err(e)) => error!("Failed to rehash a password: {}", e),
+                Err(e) => error!("Rehashing a password was interrupted: {}", e),
             }
         }
-        None
+        Ok(user)
+    }
+
+    /// Give the user a new [`Session`], from the client they logged in with.
+    pub async fn start_session(keyring: &RwLock<Self>, user: &UserRecord, client: ClientInfo) -> Session {
+        // generate them a user id
+        let user_id = Uuid::from(uuid::Uuid::new_v4());
+        let session = Session::new(user_id, user.id, user.login.clone(), crate::now(), client);
+        keyring.write().await.ring.save(&session);
+        session
     }
 
     pub fn logout(&mut self, session: &Session) {
         self.ring.discard(session)
     }
 
-    pub fn get_username_by_uuid(&self, uuid: &Uuid) -> Option<String> {
+    /// Log an account out of all of its sessions, except for `keep`.
+    pub fn logout_account(&mut self, account_id: i32, keep: Option<&Session>) {
+        self.ring.discard_account(account_id, keep.map(|s| &s.uuid))
+    }
+
+    /// Their email changed, make every session of theirs agree.
+    pub fn update_email(&mut self, account_id: i32, email: &str) {
+        self.ring.update_email(account_id, email)
+    }
+
+    /// Every session the account is logged in with.
+    pub fn sessions_of(&self, account_id: i32) -> Vec<(Uuid, SessionData)> {
+        self.ring.sessions_of(account_id)
+    }
+
+    pub fn get_by_uuid(&self, uuid: &Uuid) -> Option<SessionData> {
         self.ring.value_by_key(uuid)
     }
 }
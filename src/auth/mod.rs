@@ -1,2 +1,3 @@
 pub mod authentication;
-pub mod keyring;
\ No newline at end of file
+pub mod keyring;
+pub mod user;
\ No newline at end of file
@@ -0,0 +1,105 @@
+use rocket::{request::{self, FromRequest, Outcome}, Request, http::Status};
+use tracing::*;
+
+use super::authentication::{LoginError, Session};
+use crate::store::{ManagedStore, UserRecord};
+
+/// Whatever went wrong while loading a user, as given by [`UserLoader::load`].
+pub type LoadError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Loads your own user type for a logged in [`Session`], so handlers don't have
+/// to look them up themselves. See [`AuthUser`].
+#[rocket::async_trait]
+pub trait UserLoader: Sized + Clone + Send + Sync + 'static {
+    /// `None` if the account behind the session doesn't have a user anymore.
+    async fn load(session: &Session, request: &Request<'_>) -> Result<Option<Self>, LoadError>;
+}
+
+/// Loads the account straight out of the managed [`crate::store::UserStore`].
+#[rocket::async_trait]
+impl UserLoader for UserRecord {
+    async fn load(session: &Session, request: &Request<'_>) -> Result<Option<Self>, LoadError> {
+        match request.rocket().state::<ManagedStore>() {
+            Some(store) => Ok(store.find_by_id(session.account_id).await?),
+            None => Err("No user store is being managed".into()),
+        }
+    }
+}
+
+/// The logged in user, along with their session.
+/// # As a Request Guard
+/// This first gets a [`Session`] (logging them in the same way), then loads `U`
+/// with its [`UserLoader`]. It's only loaded once per request, no matter how
+/// many guards ask for it.
+/// ```no_run
+/// # use rocket::get;
+/// use rust_authentication::{AuthUser, store::UserRecord};
+///
+/// #[get("/whoami")]
+/// fn whoami(user: AuthUser<UserRecord>) -> String {
+///     user.user.login
+/// }
+/// ```
+#[derive(Clone)]
+pub struct AuthUser<U> {
+    pub session: Session,
+    pub user: U,
+}
+
+#[derive(Debug, Clone)]
+pub enum AuthUserError {
+    /// They couldn't get a session.
+    Login(LoginError),
+    /// They have a session, but the loader didn't find a user for it.
+    NotFound,
+    /// The loader failed.
+    Load(String),
+}
+
+impl AuthUserError {
+    fn status(&self) -> Status {
+        match self {
+            AuthUserError::Login(e) => e.status(),
+            AuthUserError::NotFound => Status::Unauthorized,
+            AuthUserError::Load(_)  => Status::InternalServerError,
+        }
+    }
+}
+
+/// Same idea as the session's cache, one per `U`.
+struct CachedUser<U>(Result<AuthUser<U>, AuthUserError>);
+
+impl<U: UserLoader> AuthUser<U> {
+    async fn load(request: &Request<'_>) -> Result<Self, AuthUserError> {
+        let session = match request.guard::<Session>().await {
+            Outcome::Success(session) => session,
+            Outcome::Error((_, e)) => return Err(AuthUserError::Login(e)),
+            Outcome::Forward(_) => return Err(AuthUserError::Login(LoginError::DatabaseError)),
+        };
+
+        match U::load(&session, request).await {
+            Ok(Some(user)) => Ok(Self { session, user }),
+            Ok(None) => Err(AuthUserError::NotFound),
+            Err(e) => {
+                error!("Failed to load user for account {}: {}", session.account_id, e);
+                Err(AuthUserError::Load(e.to_string()))
+            },
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, U: UserLoader> FromRequest<'r> for AuthUser<U> {
+    type Error = AuthUserError;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let cached = request.local_cache_async(async {
+            CachedUser(AuthUser::<U>::load(request).await)
+        }).await;
+
+        match &cached.0 {
+            Ok(user) => Outcome::Success(user.clone()),
+            Err(e) => Outcome::Error((e.status(), e.clone())),
+        }
+    }
+}
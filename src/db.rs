@@ -1,236 +1,670 @@
+#[cfg(any(feature = "redis", feature = "postgres"))]
 use std::env;
 
-use argon2::password_hash::{Encoding, PasswordHashString};
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+use std::collections::BTreeMap;
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+use std::time::Duration;
+
+use argon2::password_hash::{rand_core::{OsRng, RngCore}, PasswordHashString};
 use diesel::prelude::*;
-use serde::Deserialize;
-use tracing::*;
-use crate::auth::keyring::{KeyStorage, Keyring};
+use rocket::tokio::task::spawn_blocking;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::auth::authentication::Session;
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+use crate::config::{AccountBackend, PoolConfig};
+use crate::config::PasswordPolicy;
+use crate::password::Hasher;
+use crate::events::{AuthEvent, AuthListener};
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+use crate::identifier::normalize;
+use crate::ManagedState;
+use crate::store::{AccountStatus, LoginRecord, RedeemedToken, TokenPurpose, UserRecord, UserStore};
+use crate::validation::ValidationErrors;
 
-#[cfg(not(feature = "postgres"))]
-use rusqlite::params;
-#[cfg(feature = "postgres")]
-use diesel::result::Error;
-#[cfg(feature = "postgres")]
-use diesel::PgConnection;
-#[cfg(feature = "postgres")]
-use crate::schema::{self, account};
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+use diesel::r2d2::ConnectionManager;
+use diesel::result::{DatabaseErrorKind, Error};
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+#[cfg(feature = "redis")]
+use crate::auth::keyring::RedisStorage;
 
+#[cfg(feature = "redis")]
 const REDIS_DATABASE_URL: &str = "REDIS_DATABASE_URL";
-#[cfg(not(feature = "postgres"))]
 pub const SQLITE_DATABASE_LOCATION: &str = "test.sqlite";
+
+// Both sets have the same versions, just in each's own dialect.
+#[cfg(feature = "sqlite")]
+const SQLITE_MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/sqlite");
 #[cfg(feature = "postgres")]
-const POSTGRES_DATABASE_URL: &str = "DATABASE_URL";
+const POSTGRES_MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/postgres");
 
-pub fn redis_connect() -> Result<redis::Connection, redis::RedisError> {
-    let url = env::var(REDIS_DATABASE_URL).unwrap_or_else(|_| panic!("{} must be set", REDIS_DATABASE_URL));
+/// What diesel gives back when a migration fails.
+pub type MigrationError = Box<dyn std::error::Error + Send + Sync>;
+#[cfg(feature = "postgres")]
+const POSTGRES_DATABASE_URL: &str = "DATABASE_URL";
 
-    let redis = redis::Client::open(url).expect("Can't connect to redis");
-    redis.get_connection()
-}
+/// Connect to redis at `url`, or `REDIS_DATABASE_URL` if that isn't given.
+#[cfg(feature = "redis")]
+pub fn redis_connect(url: Option<&str>) -> Result<RedisStorage, ConnectionError> {
+    let url = url_or_env(url, REDIS_DATABASE_URL)?;
 
-trait AccountDatabase {
-    fn prepare(&self);
-    // TODO change this from option to result
-    fn new_user(
-        &mut self,
-        username: &str,
-        password: Vec<u8>,
-    ) -> Result<Account, AccountCreationError>;
-    fn get_account_hash(&mut self, username: &str) -> Option<PasswordHashString>;
+    let redis = redis::Client::open(url).map_err(ConnectionError::Redis)?;
+    RedisStorage::new(redis).map_err(ConnectionError::Redis)
 }
 
-#[cfg(feature = "postgres")]
-impl AccountDatabase for PgConnection {
-    fn prepare(&self) {
-        todo!()
-    }
-
-    fn new_user(&mut self, username: &str, hash: Vec<u8>) -> Result<Account, AccountCreationError> {
-        // for inserting new values into account
-        #[derive(Insertable)]
-        #[diesel(table_name = schema::account)]
-        struct New<'a> {
-            email: &'a str,
-            password_hash: Vec<u8>,
+/// Use the url from the config, if there isn't one fall back to the environment.
+#[cfg(any(feature = "redis", feature = "postgres"))]
+fn url_or_env(url: Option<&str>, key: &'static str) -> Result<String, ConnectionError> {
+    match url {
+        Some(url) => Ok(url.to_owned()),
+        None => {
+            // errors out if the .env file isn't found.
+            // ignoring the error
+            let _ = dotenvy::dotenv_override();
+            env::var(key).map_err(|_| ConnectionError::MissingUrl(key))
         }
+    }
+}
 
-        let new = New {
-            email: username,
-            password_hash: hash,
-        };
+/// Why a backend couldn't be connected to.
+#[derive(Debug)]
+pub enum ConnectionError {
+    /// There was no url in the config, and this environment variable wasn't set either.
+    MissingUrl(&'static str),
+    /// The backend was picked, but its feature wasn't enabled.
+    NotCompiled(&'static str),
+    #[cfg(feature = "redis")]
+    Redis(redis::RedisError),
+    /// No connection could be had from the pool in time.
+    Pool(r2d2::Error),
+    /// The blocking task using the connection didn't finish.
+    Interrupted,
+    /// The schema couldn't be brought up to date.
+    Migration(MigrationError),
+}
 
-        let result = diesel::insert_into(account::table)
-            .values(new)
-            .get_result(self);
-        match result {
-            Ok(x) => Ok(x),
-            Err(e) => match e {
-                Error::DatabaseError(a, _) => match a {
-                    diesel::result::DatabaseErrorKind::UniqueViolation => {
-                        Err(AccountCreationError::UsernameAlreadyTaken)
-                    }
-                    _ => todo!(),
-                },
-                _ => todo!(),
-            },
+impl std::fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionError::MissingUrl(key)    => write!(f, "No url was configured and {key} is not set"),
+            ConnectionError::NotCompiled(name)  => write!(f, "The '{name}' feature isn't enabled"),
+            #[cfg(feature = "redis")]
+            ConnectionError::Redis(e)           => write!(f, "Couldn't connect to redis: {e}"),
+            ConnectionError::Pool(e)            => write!(f, "Couldn't get a database connection: {e}"),
+            ConnectionError::Interrupted        => write!(f, "The database task was interrupted"),
+            ConnectionError::Migration(e)       => write!(f, "Failed to migrate the database: {e}"),
         }
     }
+}
 
-    fn get_account_hash(&mut self, username: &str) -> Option<PasswordHashString> {
-        use crate::schema::account::dsl::*;
+impl std::error::Error for ConnectionError {}
 
-        let results: Vec<Account> = account
-            .filter(email.eq(username))
-            .load::<Account>(self)
-            .expect("Error loading accounts");
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+trait AccountDatabase {
+    /// Bring the schema up to date, returning the versions that got applied.
+    fn run_migrations(&mut self) -> Result<Vec<String>, MigrationError>;
+    /// Every migration version that has been applied so far.
+    fn applied_migrations(&mut self) -> Result<Vec<String>, MigrationError>;
+    /// Fill in the missing login keys, returning the accounts that collide instead.
+    fn normalize_logins(&mut self) -> Result<Vec<LoginCollision>, AccountStoreError>;
+    fn find_by_login(&mut self, login: &str) -> Result<Option<UserRecord>, AccountStoreError>;
+    fn find_by_id(&mut self, id: i32) -> Result<Option<UserRecord>, AccountStoreError>;
+    fn create(&mut self, login: &str, password_hash: &str) -> Result<UserRecord, AccountStoreError>;
+    fn update_credentials(&mut self, id: i32, password_hash: &str) -> Result<(), AccountStoreError>;
+    fn update_login(&mut self, id: i32, login: &str) -> Result<(), AccountStoreError>;
+    fn mark_verified(&mut self, id: i32) -> Result<(), AccountStoreError>;
+    fn set_status(&mut self, id: i32, status: AccountStatus) -> Result<(), AccountStoreError>;
+    fn set_expiry(&mut self, id: i32, expires_at: Option<i64>) -> Result<(), AccountStoreError>;
+    fn mark_deleted(&mut self, id: i32, at: i64) -> Result<(), AccountStoreError>;
+    fn purge_deleted(&mut self, before: i64) -> Result<Vec<UserRecord>, AccountStoreError>;
+    fn record_login(&mut self, id: i32, login: &LoginRecord) -> Result<(), AccountStoreError>;
+    fn login_history(&mut self, id: i32) -> Result<Vec<LoginRecord>, AccountStoreError>;
+    fn create_token(&mut self, id: i32, purpose: TokenPurpose, token_hash: &[u8], expires_at: i64, data: Option<&str>) -> Result<(), AccountStoreError>;
+    fn take_token(&mut self, purpose: TokenPurpose, token_hash: &[u8], now: i64) -> Result<Option<RedeemedToken>, AccountStoreError>;
+    fn peek_token(&mut self, purpose: TokenPurpose, token_hash: &[u8], now: i64) -> Result<Option<RedeemedToken>, AccountStoreError>;
+    fn has_token(&mut self, id: i32, purpose: TokenPurpose, now: i64) -> Result<bool, AccountStoreError>;
+}
 
-        if let Some(sql_results) = results.into_iter().next() {
-            let hash_string: String = sql_results
-                .password_hash
-                .iter()
-                .map(|f| *f as char)
-                .collect();
-            // IDK what encoding is actually used
-            if let Ok(hash) = argon2::PasswordHash::parse(&hash_string, Encoding::B64) {
-                return Some(hash.serialize());
+/// Both backends go through Diesel with the same schema, so they share the
+/// exact same implementation. Only the migrations differ.
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+macro_rules! account_database {
+    ($conn:ty, $migrations:expr) => {
+        impl AccountDatabase for $conn {
+            fn run_migrations(&mut self) -> Result<Vec<String>, MigrationError> {
+                let applied = self.run_pending_migrations($migrations)?;
+                Ok(applied.iter().map(|v| v.to_string()).collect())
+            }
+
+            fn applied_migrations(&mut self) -> Result<Vec<String>, MigrationError> {
+                let applied = MigrationHarness::applied_migrations(self)?;
+                Ok(applied.iter().map(|v| v.to_string()).collect())
             }
-        }
-        None
-    }
-}
 
+            fn normalize_logins(&mut self) -> Result<Vec<LoginCollision>, AccountStoreError> {
+                use crate::schema::account::dsl::*;
 
-#[cfg(not(feature = "postgres"))]
-impl AccountDatabase for rusqlite::Connection {
-    fn prepare(&self) {
-        if let Err(e) = self.execute(include_str!("new.sql"), []) {
-            error!("Failed to prepare the sqlite database. {}", e);
-            panic!("Sqlite database preparation failed.");
-        }
-    }
+                self.transaction(|conn| {
+                    let rows = account
+                        .select((id, email, login_key))
+                        .order(id.asc())
+                        .load::<(i32, String, Option<String>)>(conn)?;
 
-    fn new_user(
-        &mut self,
-        username: &str,
-        hashed_password: Vec<u8>,
-    ) -> Result<Account, AccountCreationError> {
-        match self.execute(
-            "INSERT INTO account (username, password_hash) VALUES (?1, ?2)",
-            (username, hashed_password),
-        ) {
-            Ok(_) => {
-                let mut statement = self
-                    .prepare(
-                        "SELECT id, username, password_hash FROM account WHERE username == (?1)",
-                    )
-                    .expect("Prepared sql statement failed?");
-
-                let query_rows = statement.query_map(params![username], |row| {
-                    Ok(Account {
-                        // these names need to line up with the ones in new.sql
-                        id: row.get("id").unwrap(),
-                        email: row.get("username").unwrap(),
-                        password_hash: row.get("password_hash").unwrap(),
-                    })
-                });
-                match query_rows {
-                    // output from select statement
-                    Ok(x) => {
-                        if let Some(next) = x.into_iter().next() {
-                            match next {
-                                Ok(acct) => return Ok(acct),
-                                Err(_) => error!("Failed to get user just after creating it."),
-                            }
+                    // Every account that has, or would have, each key.
+                    let mut keys: BTreeMap<String, Vec<(i32, String, bool)>> = BTreeMap::new();
+                    for (user_id, user_email, key) in rows {
+                        let missing = key.is_none();
+                        let key = key.unwrap_or_else(|| normalize(&user_email));
+                        keys.entry(key).or_default().push((user_id, user_email, missing));
+                    }
+
+                    let mut collisions = Vec::new();
+                    for (key, accounts) in keys {
+                        // The oldest one gets the key when none of them have it, so the
+                        // login can't be taken by yet another account.
+                        if accounts.iter().all(|(_, _, missing)| *missing) {
+                            diesel::update(account.find(accounts[0].0))
+                                .set(login_key.eq(&key))
+                                .execute(conn)?;
+                        }
+                        if accounts.len() > 1 {
+                            collisions.push(LoginCollision {
+                                key,
+                                accounts: accounts.into_iter().map(|(user_id, user_email, _)| (user_id, user_email)).collect(),
+                            });
                         }
                     }
-                    Err(_) => error!("Failed to submit request to get user just after creating it."),
-                }
+                    Ok(collisions)
+                })
             }
-            Err(e) => {
-                use rusqlite::ErrorCode;
-                match e {
-                    rusqlite::Error::SqliteFailure(a, b) => match a.code {
-                        ErrorCode::OutOfMemory |
-                        ErrorCode::SystemIoFailure |
-                        ErrorCode::DiskFull |
-                        ErrorCode::CannotOpen => {
-                            error!("Hardware issue preventing write access to the database! {:?} {:?}", b, a.code);
-                        },
-                        ErrorCode::ConstraintViolation => {
-                            return Err(AccountCreationError::UsernameAlreadyTaken)
-                        }
-                        e => error!("Sqlite failure {:?}", e),
-                    },
-                    e => error!("General rusqlite error: {}", e),
+
+            fn find_by_login(&mut self, login: &str) -> Result<Option<UserRecord>, AccountStoreError> {
+                use crate::schema::account::dsl::*;
+
+                let found = account
+                    .filter(login_key.eq(normalize(login)))
+                    .select(Account::as_select())
+                    .first(self)
+                    .optional()?;
+                // Accounts that collided when the keys were filled in only have their exact email.
+                let found = match found {
+                    Some(found) => Some(found),
+                    None => account
+                        .filter(login_key.is_null())
+                        .filter(email.eq(login.trim()))
+                        .select(Account::as_select())
+                        .first(self)
+                        .optional()?,
                 };
+                found.map(Account::into_record).transpose()
+            }
+
+            fn find_by_id(&mut self, user_id: i32) -> Result<Option<UserRecord>, AccountStoreError> {
+                use crate::schema::account::dsl::*;
+
+                account
+                    .find(user_id)
+                    .select(Account::as_select())
+                    .first(self)
+                    .optional()?
+                    .map(Account::into_record)
+                    .transpose()
+            }
+
+            fn create(&mut self, login: &str, hash: &str) -> Result<UserRecord, AccountStoreError> {
+                use crate::schema::account::dsl::*;
+
+                let key = normalize(login);
+                diesel::insert_into(account)
+                    .values((email.eq(login.trim()), login_key.eq(&key), password_hash.eq(hash.as_bytes())))
+                    .execute(self)?;
+
+                account
+                    .filter(login_key.eq(key))
+                    .select(Account::as_select())
+                    .first(self)?
+                    .into_record()
+            }
+
+            fn update_credentials(&mut self, user_id: i32, hash: &str) -> Result<(), AccountStoreError> {
+                use crate::schema::account::dsl::*;
+
+                let updated = diesel::update(account.find(user_id))
+                    .set(password_hash.eq(hash.as_bytes()))
+                    .execute(self)?;
+
+                match updated {
+                    0 => Err(AccountStoreError::NotFound),
+                    _ => Ok(()),
+                }
+            }
+
+            fn update_login(&mut self, user_id: i32, login: &str) -> Result<(), AccountStoreError> {
+                use crate::schema::account::dsl::*;
+
+                let updated = diesel::update(account.find(user_id))
+                    .set((email.eq(login.trim()), login_key.eq(normalize(login))))
+                    .execute(self)?;
+
+                match updated {
+                    0 => Err(AccountStoreError::NotFound),
+                    _ => Ok(()),
+                }
             }
-        }
-        Err(AccountCreationError::Unknown)
-    }
 
-    fn get_account_hash(&mut self, username: &str) -> Option<PasswordHashString> {
-        let mut statement = self
-            .prepare("SELECT (password_hash) FROM account WHERE username == (?1)")
-            .expect("Prepared statement failed? Worked in testing...");
+            fn mark_verified(&mut self, user_id: i32) -> Result<(), AccountStoreError> {
+                use crate::schema::account::dsl::*;
 
-        let query_row = statement.query_map(params![username], |row| {
-            let hash: Vec<u8> = row.get("password_hash").unwrap();
-            let hash = hash.iter().map(|f| *f as char).collect::<String>();
+                let updated = diesel::update(account.find(user_id))
+                    .set(email_verified.eq(true))
+                    .execute(self)?;
 
-            if let Ok(hash) = argon2::PasswordHash::parse(&hash, Encoding::B64) {
-                Ok(Some(hash.serialize()))
-            } else {
-                Ok(None)
+                match updated {
+                    0 => Err(AccountStoreError::NotFound),
+                    _ => Ok(()),
+                }
+            }
+
+            fn set_status(&mut self, user_id: i32, new_status: AccountStatus) -> Result<(), AccountStoreError> {
+                use crate::schema::account::dsl::*;
+
+                let updated = diesel::update(account.find(user_id))
+                    .set(status.eq(new_status.as_str()))
+                    .execute(self)?;
+
+                match updated {
+                    0 => Err(AccountStoreError::NotFound),
+                    _ => Ok(()),
+                }
+            }
+
+            fn set_expiry(&mut self, user_id: i32, expires: Option<i64>) -> Result<(), AccountStoreError> {
+                use crate::schema::account::dsl::*;
+
+                let updated = diesel::update(account.find(user_id))
+                    .set(expires_at.eq(expires))
+                    .execute(self)?;
+
+                match updated {
+                    0 => Err(AccountStoreError::NotFound),
+                    _ => Ok(()),
+                }
             }
-        });
 
-        match query_row {
-            Ok(rows) => {
-                rows
+            fn mark_deleted(&mut self, user_id: i32, at: i64) -> Result<(), AccountStoreError> {
+                use crate::schema::account::dsl::*;
+
+                let updated = diesel::update(account.find(user_id))
+                    .set(deleted_at.eq(at))
+                    .execute(self)?;
+
+                match updated {
+                    0 => Err(AccountStoreError::NotFound),
+                    _ => Ok(()),
+                }
+            }
+
+            fn purge_deleted(&mut self, before: i64) -> Result<Vec<UserRecord>, AccountStoreError> {
+                use crate::schema::account::dsl::*;
+
+                self.transaction(|conn| {
+                    let purged = account
+                        .filter(deleted_at.le(before))
+                        .select(Account::as_select())
+                        .load(conn)?;
+                    // Their tokens go along with them.
+                    diesel::delete(account.filter(id.eq_any(purged.iter().map(|a| a.id))))
+                        .execute(conn)?;
+
+                    purged.into_iter().map(Account::into_record).collect()
+                })
+            }
+
+            fn record_login(&mut self, user_id: i32, login: &LoginRecord) -> Result<(), AccountStoreError> {
+                use crate::schema::login_history::dsl::*;
+
+                diesel::insert_into(login_history)
+                    .values((
+                        account_id.eq(user_id),
+                        at.eq(login.at),
+                        ip.eq(login.ip.as_deref()),
+                        user_agent.eq(login.user_agent.as_deref()),
+                    ))
+                    .execute(self)?;
+                Ok(())
+            }
+
+            fn login_history(&mut self, user_id: i32) -> Result<Vec<LoginRecord>, AccountStoreError> {
+                use crate::schema::login_history::dsl::*;
+
+                let history = login_history
+                    .filter(account_id.eq(user_id))
+                    .order(id.asc())
+                    .select((at, ip, user_agent))
+                    .load::<(i64, Option<String>, Option<String>)>(self)?;
+                Ok(history
                     .into_iter()
-                    .filter_map(|f| f.ok())
-                    .flatten()
-                    .next()
+                    .map(|(login_at, login_ip, agent)| LoginRecord { at: login_at, ip: login_ip, user_agent: agent })
+                    .collect())
+            }
+
+            fn create_token(&mut self, user_id: i32, token_purpose: TokenPurpose, hash: &[u8], expires: i64, token_data: Option<&str>) -> Result<(), AccountStoreError> {
+                use crate::schema::{account, account_token::dsl::*};
+
+                self.transaction(|conn| {
+                    account::table.find(user_id).select(account::id).first::<i32>(conn)?;
+
+                    // Only the newest token for something is any good.
+                    if token_purpose.replaces_earlier() {
+                        diesel::delete(account_token
+                            .filter(account_id.eq(user_id))
+                            .filter(purpose.eq(token_purpose.as_str())))
+                            .execute(conn)?;
+                    }
+
+                    diesel::insert_into(account_token)
+                        .values((
+                            account_id.eq(user_id),
+                            purpose.eq(token_purpose.as_str()),
+                            token_hash.eq(hash),
+                            expires_at.eq(expires),
+                            data.eq(token_data),
+                        ))
+                        .execute(conn)?;
+                    Ok(())
+                })
+            }
+
+            fn take_token(&mut self, token_purpose: TokenPurpose, hash: &[u8], now: i64) -> Result<Option<RedeemedToken>, AccountStoreError> {
+                use crate::schema::account_token::dsl::*;
+
+                let found = account_token
+                    .filter(purpose.eq(token_purpose.as_str()))
+                    .filter(token_hash.eq(hash))
+                    .select((id, account_id, expires_at, data))
+                    .first::<(i32, i32, i64, Option<String>)>(self)
+                    .optional()?;
+                let Some((token_id, user_id, expires, token_data)) = found else {
+                    return Ok(None);
+                };
+
+                // Whoever deletes it gets to use it, so it can't be used twice at once.
+                let deleted = diesel::delete(account_token.find(token_id)).execute(self)?;
+                Ok((deleted == 1 && expires > now).then_some(RedeemedToken { account_id: user_id, data: token_data }))
+            }
+
+            fn peek_token(&mut self, token_purpose: TokenPurpose, hash: &[u8], now: i64) -> Result<Option<RedeemedToken>, AccountStoreError> {
+                use crate::schema::account_token::dsl::*;
+
+                let found = account_token
+                    .filter(purpose.eq(token_purpose.as_str()))
+                    .filter(token_hash.eq(hash))
+                    .filter(expires_at.gt(now))
+                    .select((account_id, data))
+                    .first::<(i32, Option<String>)>(self)
+                    .optional()?;
+                Ok(found.map(|(user_id, token_data)| RedeemedToken { account_id: user_id, data: token_data }))
+            }
+
+            fn has_token(&mut self, user_id: i32, token_purpose: TokenPurpose, now: i64) -> Result<bool, AccountStoreError> {
+                use crate::schema::account_token::dsl::*;
+
+                let found = account_token
+                    .filter(account_id.eq(user_id))
+                    .filter(purpose.eq(token_purpose.as_str()))
+                    .filter(expires_at.gt(now))
+                    .select(id)
+                    .first::<i32>(self)
+                    .optional()?;
+                Ok(found.is_some())
             }
-            Err(e) => {
-                error!("{:?}", e);
-                None
-            },
         }
-    }
+    };
 }
 
-fn establish_connection() -> impl AccountDatabase {
-    // errors out if the .env file isn't found.
-    // ignoring the error
-    let _ = dotenvy::dotenv_override();
+#[cfg(feature = "postgres")]
+account_database!(PgConnection, POSTGRES_MIGRATIONS);
+
+#[cfg(feature = "sqlite")]
+account_database!(SqliteConnection, SQLITE_MIGRATIONS);
+
+/// A pool of connections to the account database that was picked in the config.
+/// [`crate::AuthFairing`] will manage this for you.
+///
+/// Cloning this is cheap, the clones share the same pool.
+///
+/// Only there with the `sqlite` or `postgres` feature. Without either of them,
+/// manage your own [`crate::store::ManagedStore`].
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+#[derive(Clone)]
+pub struct AccountDb {
+    pool: Pool,
+}
 
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+#[derive(Clone)]
+enum Pool {
+    #[cfg(feature = "sqlite")]
+    Sqlite(r2d2::Pool<ConnectionManager<SqliteConnection>>),
     #[cfg(feature = "postgres")]
+    Postgres(r2d2::Pool<ConnectionManager<PgConnection>>),
+}
+
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+impl AccountDb {
+    /// Set up the pool. No connections are made until they are needed,
+    /// use [`AccountDb::check`] to make sure the database can be reached.
+    pub fn new(backend: &AccountBackend, config: &PoolConfig) -> Result<Self, ConnectionError> {
+        let pool = match backend {
+            #[cfg(feature = "postgres")]
+            AccountBackend::Postgres { url } => {
+                let database_url = url_or_env(url.as_deref(), POSTGRES_DATABASE_URL)?;
+                Pool::Postgres(Self::builder(config).build_unchecked(ConnectionManager::new(database_url)))
+            },
+            #[cfg(feature = "sqlite")]
+            AccountBackend::Sqlite { path } => {
+                // Other connections in the pool might be writing, wait for them instead of failing.
+                let busy_timeout = config.connect_timeout * 1000;
+                let pool = Self::builder(config)
+                    .connection_customizer(Box::new(SqlitePragmas { busy_timeout }))
+                    .build_unchecked(ConnectionManager::new(path));
+                Pool::Sqlite(pool)
+            },
+            #[cfg(not(feature = "postgres"))]
+            AccountBackend::Postgres { .. } => return Err(ConnectionError::NotCompiled("postgres")),
+            #[cfg(not(feature = "sqlite"))]
+            AccountBackend::Sqlite { .. } => return Err(ConnectionError::NotCompiled("sqlite")),
+        };
+        Ok(Self { pool })
+    }
+
+    fn builder<M: r2d2::ManageConnection>(config: &PoolConfig) -> r2d2::Builder<M> {
+        r2d2::Pool::builder()
+            .max_size(config.max_connections)
+            .min_idle(config.min_idle)
+            .connection_timeout(Duration::from_secs(config.connect_timeout))
+            .idle_timeout(config.idle_timeout.map(Duration::from_secs))
+    }
+
+    /// Make sure the database can actually be reached.
+    pub async fn check(&self) -> Result<(), ConnectionError> {
+        self.run(|_| ()).await
+    }
+
+    /// Apply any migrations that haven't been yet, returning their versions.
+    /// [`crate::AuthFairing`] does this at startup unless told otherwise.
+    ///
+    /// SQLite databases from before there were migrations get their `account`
+    /// table rebuilt by the first one, `username` becoming `email`.
+    pub async fn run_migrations(&self) -> Result<Vec<String>, ConnectionError> {
+        self.run(|conn| conn.run_migrations()).await?
+            .map_err(ConnectionError::Migration)
+    }
+
+    /// Fill in the normalised login of any account that is missing one (ex: made
+    /// before logins were normalised), see [`crate::identifier::normalize`].
+    /// [`crate::AuthFairing`] does this after running the migrations.
+    ///
+    /// Accounts that would end up with the same login are returned. The oldest of them
+    /// gets it, the others can only log in with their exact email until they are sorted out.
+    pub async fn normalize_logins(&self) -> Result<Vec<LoginCollision>, AccountStoreError> {
+        self.run(|conn| conn.normalize_logins()).await?
+    }
+
+    /// Every migration version that has been applied to the database.
+    pub async fn applied_migrations(&self) -> Result<Vec<String>, ConnectionError> {
+        self.run(|conn| conn.applied_migrations()).await?
+            .map_err(ConnectionError::Migration)
+    }
+
+    /// Run `f` with a connection from the pool, on a thread where it's ok to block.
+    async fn run<F, T>(&self, f: F) -> Result<T, ConnectionError>
+    where
+        F: FnOnce(&mut dyn AccountDatabase) -> T + Send + 'static,
+        T: Send + 'static,
     {
-        // the env should be loaded into ram at this point, so there shouldn't be problems running this lots
-        let database_url = env::var(POSTGRES_DATABASE_URL)
-            .expect(&format!("{} must be set!", POSTGRES_DATABASE_URL));
+        let pool = self.pool.clone();
+        spawn_blocking(move || pool.with_connection(f))
+            .await
+            .map_err(|_| ConnectionError::Interrupted)?
+    }
+}
 
-        PgConnection::establish(&database_url)
-            .unwrap_or_else(|_| panic!("Error connecting to {}", database_url))
+/// Settings sqlite needs on every connection, it doesn't remember them.
+#[cfg(feature = "sqlite")]
+#[derive(Debug)]
+struct SqlitePragmas {
+    /// How long to wait on a locked database, in milliseconds.
+    busy_timeout: u64,
+}
+
+#[cfg(feature = "sqlite")]
+impl r2d2::CustomizeConnection<SqliteConnection, diesel::r2d2::Error> for SqlitePragmas {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), diesel::r2d2::Error> {
+        diesel::sql_query(format!("PRAGMA busy_timeout = {};", self.busy_timeout))
+            .execute(conn)
+            .map_err(diesel::r2d2::Error::QueryError)?;
+        // Off by default, without it tokens would outlive their account.
+        diesel::sql_query("PRAGMA foreign_keys = ON;")
+            .execute(conn)
+            .map(|_| ())
+            .map_err(diesel::r2d2::Error::QueryError)
     }
+}
 
-    #[cfg(not(feature = "postgres"))]
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+impl Pool {
+    fn with_connection<F, T>(&self, f: F) -> Result<T, ConnectionError>
+    where
+        F: FnOnce(&mut dyn AccountDatabase) -> T,
     {
-        rusqlite::Connection::open(SQLITE_DATABASE_LOCATION)
-            .expect("Failed to open sqlite database. Cannot continue.")
+        match self {
+            #[cfg(feature = "sqlite")]
+            Pool::Sqlite(pool) => {
+                let mut conn = pool.get().map_err(ConnectionError::Pool)?;
+                Ok(f(&mut *conn))
+            },
+            #[cfg(feature = "postgres")]
+            Pool::Postgres(pool) => {
+                let mut conn = pool.get().map_err(ConnectionError::Pool)?;
+                Ok(f(&mut *conn))
+            },
+        }
+    }
+}
+
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+#[rocket::async_trait]
+impl UserStore for AccountDb {
+    async fn find_by_login(&self, login: &str) -> Result<Option<UserRecord>, AccountStoreError> {
+        let login = login.to_owned();
+        self.run(move |conn| conn.find_by_login(&login)).await?
+    }
+
+    async fn find_by_id(&self, id: i32) -> Result<Option<UserRecord>, AccountStoreError> {
+        self.run(move |conn| conn.find_by_id(id)).await?
+    }
+
+    async fn create(&self, login: &str, password_hash: &str) -> Result<UserRecord, AccountStoreError> {
+        let (login, password_hash) = (login.to_owned(), password_hash.to_owned());
+        self.run(move |conn| conn.create(&login, &password_hash)).await?
+    }
+
+    async fn update_credentials(&self, id: i32, password_hash: &str) -> Result<(), AccountStoreError> {
+        let password_hash = password_hash.to_owned();
+        self.run(move |conn| conn.update_credentials(id, &password_hash)).await?
+    }
+
+    async fn update_login(&self, id: i32, login: &str) -> Result<(), AccountStoreError> {
+        let login = login.to_owned();
+        self.run(move |conn| conn.update_login(id, &login)).await?
+    }
+
+    async fn mark_verified(&self, id: i32) -> Result<(), AccountStoreError> {
+        self.run(move |conn| conn.mark_verified(id)).await?
+    }
+
+    async fn set_status(&self, id: i32, status: AccountStatus) -> Result<(), AccountStoreError> {
+        self.run(move |conn| conn.set_status(id, status)).await?
+    }
+
+    async fn set_expiry(&self, id: i32, expires_at: Option<i64>) -> Result<(), AccountStoreError> {
+        self.run(move |conn| conn.set_expiry(id, expires_at)).await?
+    }
+
+    async fn mark_deleted(&self, id: i32, at: i64) -> Result<(), AccountStoreError> {
+        self.run(move |conn| conn.mark_deleted(id, at)).await?
+    }
+
+    async fn purge_deleted(&self, before: i64) -> Result<Vec<UserRecord>, AccountStoreError> {
+        self.run(move |conn| conn.purge_deleted(before)).await?
+    }
+
+    async fn record_login(&self, id: i32, login: LoginRecord) -> Result<(), AccountStoreError> {
+        self.run(move |conn| conn.record_login(id, &login)).await?
+    }
+
+    async fn login_history(&self, id: i32) -> Result<Vec<LoginRecord>, AccountStoreError> {
+        self.run(move |conn| conn.login_history(id)).await?
+    }
+
+    async fn create_token(&self, id: i32, purpose: TokenPurpose, token_hash: Vec<u8>, expires_at: i64, data: Option<String>) -> Result<(), AccountStoreError> {
+        self.run(move |conn| conn.create_token(id, purpose, &token_hash, expires_at, data.as_deref())).await?
+    }
+
+    async fn take_token(&self, purpose: TokenPurpose, token_hash: &[u8], now: i64) -> Result<Option<RedeemedToken>, AccountStoreError> {
+        let token_hash = token_hash.to_owned();
+        self.run(move |conn| conn.take_token(purpose, &token_hash, now)).await?
+    }
+
+    async fn peek_token(&self, purpose: TokenPurpose, token_hash: &[u8], now: i64) -> Result<Option<RedeemedToken>, AccountStoreError> {
+        let token_hash = token_hash.to_owned();
+        self.run(move |conn| conn.peek_token(purpose, &token_hash, now)).await?
+    }
+
+    async fn has_token(&self, id: i32, purpose: TokenPurpose, now: i64) -> Result<bool, AccountStoreError> {
+        self.run(move |conn| conn.has_token(id, purpose, now)).await?
     }
 }
 
 //=======================================
 //             Account
 //=======================================
-#[derive(Queryable)]
-#[allow(dead_code)]
+/// A row of the `account` table.
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = crate::schema::account)]
+#[cfg_attr(not(any(feature = "sqlite", feature = "postgres")), allow(dead_code))]
 pub struct Account {
     id: i32,
     email: String,
     password_hash: Vec<u8>,
+    email_verified: bool,
+    deleted_at: Option<i64>,
+    status: String,
+    expires_at: Option<i64>,
 }
 
 #[derive(Deserialize, Copy, Clone)]
@@ -239,22 +673,276 @@ pub struct NewAccount<'a> {
     password: &'a str,
 }
 
+impl NewAccount<'_> {
+    /// The `name` has to be an email, and the password has to follow the `policy`.
+    /// [`Account::create`] doesn't check this itself, so accounts can still be
+    /// made some other way.
+    pub async fn validate(&self, policy: &PasswordPolicy) -> Result<(), ValidationErrors> {
+        ValidationErrors::new()
+            .check_email("name", self.name)
+            .check_password("password", self.password, policy, Some(self.name))
+            .check_breached("password", self.password, policy)
+            .await
+            .into_result()
+    }
+}
+
 impl Account {
-    pub fn new(account: NewAccount<'_>) -> Result<Self, AccountCreationError> {
-        let mut conn = establish_connection();
-        let hash = Keyring::<dyn KeyStorage>::hash_password(account.password);
+    /// Hash the password with `hasher` and add the account to `store`.
+    pub async fn create(store: &dyn UserStore, account: NewAccount<'_>, hasher: &Hasher) -> Result<UserRecord, AccountStoreError> {
+        let hash = Self::hash(account.password, hasher).await?;
+        store.create(account.name, hash.as_str()).await
+    }
+
+    /// Add an account from another system, keeping the hash it had there. It has to
+    /// be something `hasher` can verify, see [`Hasher::can_verify`], and gets rehashed
+    /// with Argon2id the first time they log in.
+    pub async fn import(store: &dyn UserStore, login: &str, password_hash: &str, hasher: &Hasher) -> Result<UserRecord, AccountStoreError> {
+        if !hasher.can_verify(password_hash) {
+            return Err(AccountStoreError::Hash("the password hash isn't in a format that can be verified".to_string()));
+        }
+        store.create(login, password_hash).await
+    }
+
+    /// Hash the new password with `hasher` and store it in place of the old one.
+    pub async fn change_password(store: &dyn UserStore, account_id: i32, new_password: &str, hasher: &Hasher) -> Result<(), AccountStoreError> {
+        let hash = Self::hash(new_password, hasher).await?;
+        store.update_credentials(account_id, hash.as_str()).await
+    }
+
+    /// Is this the account's password? Fails with [`AccountStoreError::NotFound`] if
+    /// the account doesn't exist.
+    pub async fn check_password(store: &dyn UserStore, account_id: i32, password: &str, hasher: &Hasher) -> Result<bool, AccountStoreError> {
+        let user = store.find_by_id(account_id).await?.ok_or(AccountStoreError::NotFound)?;
+
+        let password = password.to_owned();
+        let hasher = hasher.clone();
+        spawn_blocking(move || hasher.verify(&password, &user.password_hash))
+            .await
+            .map_err(|_| ConnectionError::Interrupted.into())
+    }
+
+    /// Everything that is known about the account, for handing over to them.
+    /// Secrets (the password hash, session ids and tokens) are left out.
+    /// `current` marks the session that asked for it. If the store doesn't keep a
+    /// login history, it's left empty. Linked identities aren't included, see [`AccountExport`].
+    pub async fn export(store: &dyn UserStore, keyring: &ManagedState, account_id: i32, current: Option<&Session>) -> Result<AccountExport, AccountStoreError> {
+        let user = store.find_by_id(account_id).await?.ok_or(AccountStoreError::NotFound)?;
+        let login_history = match store.login_history(account_id).await {
+            Err(AccountStoreError::Unsupported(_)) => Vec::new(),
+            history => history?,
+        };
+
+        let mut sessions = keyring.read().await
+            .sessions_of(account_id)
+            .into_iter()
+            .map(|(uuid, data)| ExportedSession {
+                created_at: data.created_at,
+                ip: data.client.ip,
+                user_agent: data.client.user_agent,
+                current: current.is_some_and(|s| s.uuid == uuid),
+            })
+            .collect::<Vec<_>>();
+        sessions.sort_by_key(|s| s.created_at);
 
-        conn.prepare();
-        conn.new_user(account.name, Vec::from(hash.to_string()))
+        Ok(AccountExport {
+            id: user.id,
+            email: user.login,
+            verified: user.verified,
+            deleted_at: user.deleted_at,
+            status: user.status,
+            expires_at: user.expires_at,
+            sessions,
+            login_history,
+        })
     }
 
-    pub fn get_account_hash(mail: &str) -> Option<PasswordHashString> {
-        let mut conn = establish_connection();
-        conn.get_account_hash(mail)
+    /// Soft-delete the account, it can't be logged into from now on and gets purged
+    /// once `grace` seconds have passed. Returns when that will be.
+    pub async fn delete(store: &dyn UserStore, account_id: i32, grace: u64) -> Result<i64, AccountStoreError> {
+        let now = crate::now();
+        store.mark_deleted(account_id, now).await?;
+        Ok(now + grace as i64)
     }
+
+    /// Purge every account that was deleted more than `grace` seconds ago,
+    /// telling `listener` about each of them. Returns how many were purged.
+    pub async fn purge_deleted(store: &dyn UserStore, grace: u64, listener: &dyn AuthListener) -> Result<usize, AccountStoreError> {
+        let purged = store.purge_deleted(crate::now() - grace as i64).await?;
+        for user in &purged {
+            listener.on_event(&AuthEvent::AccountPurged { account_id: user.id, email: user.login.clone() }).await;
+        }
+        Ok(purged.len())
+    }
+
+    /// Make a new token that lets the account do `purpose` within the next `lifetime`
+    /// seconds. Only its hash is kept, the token itself is meant to be emailed to them.
+    /// `data` is given back when the token is redeemed.
+    pub async fn issue_token(store: &dyn UserStore, account_id: i32, purpose: TokenPurpose, lifetime: u64, data: Option<String>) -> Result<String, AccountStoreError> {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let token = hex::encode(bytes);
+
+        let expires_at = crate::now() + lifetime as i64;
+        store.create_token(account_id, purpose, Self::hash_token(&token), expires_at, data).await?;
+        Ok(token)
+    }
+
+    /// Use up a token, giving back who it was issued to.
+    /// `None` if it doesn't exist, was already used or has expired.
+    pub async fn redeem_token(store: &dyn UserStore, purpose: TokenPurpose, token: &str) -> Result<Option<RedeemedToken>, AccountStoreError> {
+        store.take_token(purpose, &Self::hash_token(token), crate::now()).await
+    }
+
+    /// Who a token was issued to, without using it up. `None` in the same cases
+    /// as [`Self::redeem_token`].
+    pub async fn peek_token(store: &dyn UserStore, purpose: TokenPurpose, token: &str) -> Result<Option<RedeemedToken>, AccountStoreError> {
+        store.peek_token(purpose, &Self::hash_token(token), crate::now()).await
+    }
+
+    /// Does the account have a token for `purpose` that can still be used?
+    pub async fn has_token(store: &dyn UserStore, account_id: i32, purpose: TokenPurpose) -> Result<bool, AccountStoreError> {
+        store.has_token(account_id, purpose, crate::now()).await
+    }
+
+    /// The tokens are random enough that a plain hash is all they need.
+    fn hash_token(token: &str) -> Vec<u8> {
+        Sha256::digest(token.as_bytes()).to_vec()
+    }
+
+    /// Hashing is slow on purpose, so this gets done off of the async threads.
+    async fn hash(password: &str, hasher: &Hasher) -> Result<PasswordHashString, AccountStoreError> {
+        let password = password.to_owned();
+        let hasher = hasher.clone();
+        spawn_blocking(move || hasher.hash(&password))
+            .await
+            .map_err(|_| AccountStoreError::from(ConnectionError::Interrupted))?
+            .map_err(|e| AccountStoreError::Hash(e.to_string()))
+    }
+
+    #[cfg(any(feature = "sqlite", feature = "postgres"))]
+    fn into_record(self) -> Result<UserRecord, AccountStoreError> {
+        Ok(UserRecord {
+            id: self.id,
+            login: self.email,
+            password_hash: Self::parse_hash(&self.password_hash)?,
+            verified: self.email_verified,
+            deleted_at: self.deleted_at,
+            status: self.status.parse()?,
+            expires_at: self.expires_at,
+        })
+    }
+
+    /// The hash is stored as the bytes of its PHC (or modular crypt) string. Whether
+    /// it's in a format that can be verified is up to the [`Hasher`].
+    #[cfg(any(feature = "sqlite", feature = "postgres"))]
+    fn parse_hash(stored: &[u8]) -> Result<String, AccountStoreError> {
+        String::from_utf8(stored.to_vec())
+            .map_err(|_| AccountStoreError::Corrupt("password hash isn't valid utf-8".to_string()))
+    }
+}
+
+/// Accounts whose logins are the same once normalised.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoginCollision {
+    /// The normalised login they share.
+    pub key: String,
+    /// The id and email of each of them.
+    pub accounts: Vec<(i32, String)>,
+}
+
+/// What [`Account::export`] gives back.
+///
+/// There's nothing about linked identities (ex: logging in with another site),
+/// as this crate doesn't link any. Apps that do should hand those over themselves.
+#[derive(Debug, Clone, Serialize)]
+pub struct AccountExport {
+    pub id: i32,
+    pub email: String,
+    pub verified: bool,
+    pub deleted_at: Option<i64>,
+    pub status: AccountStatus,
+    pub expires_at: Option<i64>,
+    /// Every session they are currently logged in with, oldest first.
+    pub sessions: Vec<ExportedSession>,
+    /// Every time they logged in, oldest first.
+    pub login_history: Vec<LoginRecord>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportedSession {
+    pub created_at: i64,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    /// Is this the session the export was asked for with?
+    pub current: bool,
 }
 
-pub enum AccountCreationError {
-    UsernameAlreadyTaken,
-    Unknown,
+/// Everything that can go wrong talking to the account database.
+#[derive(Debug, Clone)]
+pub enum AccountStoreError {
+    /// The database couldn't be reached.
+    Unavailable(String),
+    /// The account (ex: its email) already exists.
+    Conflict,
+    /// The account that was being changed doesn't exist.
+    NotFound,
+    /// Some other constraint of the schema was broken.
+    Constraint(String),
+    /// Data couldn't be converted to or from the database, or the transaction
+    /// couldn't be serialized with others. (These are safe to retry.)
+    Serialization(String),
+    /// What's in the database doesn't make sense, ex: a password hash that can't be parsed.
+    Corrupt(String),
+    /// Any other error the database gave back.
+    Database(String),
+    /// The password couldn't be hashed, ex: Argon2 didn't accept the hashing costs or pepper.
+    Hash(String),
+    /// The store doesn't do this, ex: a custom [`UserStore`] without tokens.
+    /// Holds the method that was called.
+    Unsupported(&'static str),
+}
+
+impl std::fmt::Display for AccountStoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AccountStoreError::Unavailable(e)   => write!(f, "Account database unavailable: {e}"),
+            AccountStoreError::Conflict         => write!(f, "Account already exists"),
+            AccountStoreError::NotFound         => write!(f, "Account doesn't exist"),
+            AccountStoreError::Constraint(e)    => write!(f, "Account constraint violated: {e}"),
+            AccountStoreError::Serialization(e) => write!(f, "Account serialization failure: {e}"),
+            AccountStoreError::Corrupt(e)       => write!(f, "Account data is corrupt: {e}"),
+            AccountStoreError::Database(e)      => write!(f, "Account database error: {e}"),
+            AccountStoreError::Hash(e)          => write!(f, "Failed to hash password: {e}"),
+            AccountStoreError::Unsupported(e)   => write!(f, "The account store doesn't support {e}"),
+        }
+    }
+}
+
+impl std::error::Error for AccountStoreError {}
+
+impl From<ConnectionError> for AccountStoreError {
+    fn from(e: ConnectionError) -> Self {
+        AccountStoreError::Unavailable(e.to_string())
+    }
+}
+
+impl From<Error> for AccountStoreError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::DatabaseError(kind, info) => match kind {
+                DatabaseErrorKind::UniqueViolation => AccountStoreError::Conflict,
+                DatabaseErrorKind::ForeignKeyViolation |
+                DatabaseErrorKind::NotNullViolation |
+                DatabaseErrorKind::CheckViolation => AccountStoreError::Constraint(info.message().to_string()),
+                DatabaseErrorKind::SerializationFailure => AccountStoreError::Serialization(info.message().to_string()),
+                DatabaseErrorKind::ClosedConnection => AccountStoreError::Unavailable(info.message().to_string()),
+                _ => AccountStoreError::Database(info.message().to_string()),
+            },
+            Error::NotFound => AccountStoreError::NotFound,
+            Error::SerializationError(e) |
+            Error::DeserializationError(e) => AccountStoreError::Serialization(e.to_string()),
+            e => AccountStoreError::Database(e.to_string()),
+        }
+    }
 }
@@ -0,0 +1,67 @@
+use std::sync::Arc;
+
+use tracing::*;
+
+/// What Rocket manages so the routes can tell the rest of the app what happened.
+/// [`crate::AuthFairing`] uses a [`LogListener`], unless you are already
+/// managing one of these yourself:
+/// ```no_run
+/// # use rocket::launch;
+/// use std::sync::Arc;
+/// use rust_authentication::{AuthFairing, events::{AuthEvent, AuthListener, ManagedListener}};
+///
+/// struct CleanUp;
+///
+/// #[rocket::async_trait]
+/// impl AuthListener for CleanUp {
+///     async fn on_event(&self, event: &AuthEvent) {
+///         if let AuthEvent::AccountPurged { account_id, .. } = event {
+///             // remove everything else you have on them
+///         }
+///     }
+/// }
+///
+/// #[launch]
+/// fn rocket() -> _ {
+///     rocket::build()
+///         .manage::<ManagedListener>(Arc::new(CleanUp))
+///         .attach(AuthFairing::new())
+/// }
+/// ```
+pub type ManagedListener = Arc<dyn AuthListener>;
+
+/// Something that happened to an account.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AuthEvent {
+    /// They deleted their account. It can't be logged into anymore, and
+    /// will be purged at `purge_at` (seconds since the unix epoch).
+    AccountDeleted {
+        account_id: i32,
+        email: String,
+        purge_at: i64,
+    },
+    /// A deleted account is gone for good.
+    AccountPurged {
+        account_id: i32,
+        email: String,
+    },
+}
+
+/// Gets told about every [`AuthEvent`]. Implement this to clean up your
+/// own data when an account goes away.
+#[rocket::async_trait]
+pub trait AuthListener: Send + Sync {
+    async fn on_event(&self, event: &AuthEvent);
+}
+
+/// Just logs the events.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogListener;
+
+#[rocket::async_trait]
+impl AuthListener for LogListener {
+    async fn on_event(&self, event: &AuthEvent) {
+        info!("Auth event: {:?}", event);
+    }
+}
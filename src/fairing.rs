@@ -0,0 +1,273 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use rocket::{
+    catchers,
+    fairing::{self, Fairing, Info, Kind},
+    figment::{providers::Serialized, Figment},
+    Build, Orbit, Rocket,
+};
+use tracing::*;
+
+use crate::config::{AuthConfig, AuthRoute, ConfigError, MailBackend, SessionBackend, CONFIG_KEY};
+use crate::db::{Account, AccountStoreError};
+use crate::events::{LogListener, ManagedListener};
+use crate::mail::{self, ManagedMailer};
+use crate::password::Hasher;
+use crate::{pages, store::ManagedStore};
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+use crate::AccountDb;
+
+/// Purge the deleted accounts whose grace period is over, logging how it went.
+async fn purge_deleted(accounts: &ManagedStore, listener: &ManagedListener, grace: u64) {
+    match Account::purge_deleted(accounts.as_ref(), grace, listener.as_ref()).await {
+        Ok(0) | Err(AccountStoreError::Unsupported(_)) => {},
+        Ok(purged) => info!("Purged {} deleted accounts", purged),
+        Err(e) => error!("Failed to purge deleted accounts: {}", e),
+    }
+}
+
+/// Sets everything up for you. Attach it and the keyring gets managed, the
+/// catchers registered and the routes mounted.
+/// ```no_run
+/// # use rocket::launch;
+/// use rust_authentication::{AuthFairing, config::AuthRoute};
+///
+/// #[launch]
+/// fn rocket() -> _ {
+///     rocket::build().attach(
+///         AuthFairing::new()
+///             .base_path("/auth")
+///             .without(AuthRoute::CreateAccount)
+///     )
+/// }
+/// ```
+/// Anything set here can be overridden by the `auth` table of Rocket's config.
+#[derive(Default)]
+pub struct AuthFairing {
+    config: AuthConfig,
+}
+
+impl AuthFairing {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Where to mount the routes. Defaults to `/`.
+    pub fn base_path(mut self, path: impl Into<String>) -> Self {
+        self.config.base_path = path.into();
+        self
+    }
+
+    /// Only mount these routes. Defaults to all of them.
+    pub fn routes(mut self, routes: impl IntoIterator<Item = AuthRoute>) -> Self {
+        self.config.routes = routes.into_iter().collect();
+        self
+    }
+
+    /// Don't mount this route.
+    pub fn without(mut self, route: AuthRoute) -> Self {
+        self.config.routes.retain(|r| *r != route);
+        self
+    }
+
+    /// Refuse to launch if a backend can't be reached. Defaults to `true`.
+    pub fn require_backends(mut self, require: bool) -> Self {
+        self.config.require_backends = require;
+        self
+    }
+
+    /// Apply pending migrations to the account database at startup. Defaults to `true`.
+    /// Turn this off to run them yourself with [`AccountDb::run_migrations`].
+    pub fn run_migrations(mut self, run: bool) -> Self {
+        self.config.run_migrations = run;
+        self
+    }
+
+    /// Turn away accounts that haven't verified their email. Defaults to `false`.
+    pub fn require_verified(mut self, require: bool) -> Self {
+        self.config.require_verified = require;
+        self
+    }
+
+    /// Layer Rocket's config over top of the one this fairing was built with.
+    fn read_config(&self, rocket: &Rocket<Build>) -> Result<AuthConfig, ConfigError> {
+        let config: AuthConfig = Figment::from(Serialized::defaults(&self.config))
+            .merge(rocket.figment().focus(CONFIG_KEY))
+            .extract()
+            .map_err(|e| ConfigError::Extract(Box::new(e)))?;
+
+        config.validate()?;
+        Ok(config)
+    }
+    /// Set up the account database from the config, migrating it unless told not to.
+    #[cfg(any(feature = "sqlite", feature = "postgres"))]
+    async fn manage_account_db(rocket: Rocket<Build>, config: &AuthConfig) -> fairing::Result {
+        let accounts = match AccountDb::new(&config.accounts, &config.pool) {
+            Ok(accounts) => accounts,
+            Err(e) => {
+                error!("Account database is misconfigured: {}", e);
+                return Err(rocket);
+            }
+        };
+        if let Err(e) = accounts.check().await {
+            if config.require_backends {
+                error!("Account database is unavailable: {}", e);
+                return Err(rocket);
+            }
+            warn!("Account database is unavailable: {}", e);
+            warn!("Logins and account creation will fail until it can be reached.");
+        } else if config.run_migrations {
+            match accounts.run_migrations().await {
+                Ok(applied) => for version in applied {
+                    info!("Applied account database migration {}", version);
+                },
+                Err(e) => {
+                    error!("{}", e);
+                    return Err(rocket);
+                },
+            }
+            match accounts.normalize_logins().await {
+                Ok(collisions) => for collision in collisions {
+                    error!(
+                        "Accounts {:?} all log in as '{}', only the first has it. The others can only use their exact email until this is sorted out.",
+                        collision.accounts, collision.key,
+                    );
+                },
+                Err(e) => {
+                    error!("Failed to normalize logins: {}", e);
+                    return Err(rocket);
+                },
+            }
+        }
+        Ok(rocket
+            .manage::<ManagedStore>(Arc::new(accounts.clone()))
+            .manage(accounts))
+    }
+
+    #[cfg(not(any(feature = "sqlite", feature = "postgres")))]
+    async fn manage_account_db(rocket: Rocket<Build>, _config: &AuthConfig) -> fairing::Result {
+        error!("No account database is compiled in, enable the 'sqlite' or 'postgres' feature or manage your own ManagedStore.");
+        Err(rocket)
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for AuthFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Authentication",
+            kind: Kind::Ignite | Kind::Liftoff,
+        }
+    }
+
+    /// One last check that everything can be reached before taking requests.
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        if let Some(keyring) = rocket.state::<crate::ManagedState>() {
+            if !keyring.read().await.ring.is_available() {
+                error!("Session storage can't be reached, no one will be able to log in.");
+            }
+        }
+        #[cfg(any(feature = "sqlite", feature = "postgres"))]
+        if let Some(accounts) = rocket.state::<AccountDb>() {
+            if let Err(e) = accounts.check().await {
+                error!("Account database can't be reached: {}", e);
+            }
+        }
+        let state = rocket.state::<ManagedStore>()
+            .zip(rocket.state::<ManagedListener>())
+            .zip(rocket.state::<AuthConfig>());
+        if let Some(((accounts, listener), config)) = state {
+            // Catch up on any deleted accounts whose grace period ran out while we were down,
+            // then keep purging them as their grace periods run out.
+            purge_deleted(accounts, listener, config.deletion_grace).await;
+            if config.purge_interval > 0 {
+                let (accounts, listener) = (Arc::clone(accounts), Arc::clone(listener));
+                let (grace, every) = (config.deletion_grace, Duration::from_secs(config.purge_interval));
+                let mut shutdown = rocket.shutdown();
+                rocket::tokio::spawn(async move {
+                    loop {
+                        rocket::tokio::select! {
+                            _ = rocket::tokio::time::sleep(every) => purge_deleted(&accounts, &listener, grace).await,
+                            _ = &mut shutdown => break,
+                        }
+                    }
+                });
+            }
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        let config = match self.read_config(&rocket) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("{}", e);
+                return Err(rocket);
+            }
+        };
+
+        let mut rocket = rocket;
+        // Don't want to crash if they are already managing their own.
+        if rocket.state::<crate::ManagedState>().is_none() {
+            let keyring = match crate::get_state(&config.sessions) {
+                Ok(keyring) => keyring,
+                Err(e) if config.require_backends => {
+                    error!("Session storage is unavailable: {}", e);
+                    return Err(rocket);
+                },
+                Err(e) => {
+                    warn!("Session storage is unavailable: {}", e);
+                    warn!("Falling back to keeping sessions in memory.");
+                    match crate::get_state(&SessionBackend::Memory) {
+                        Ok(keyring) => keyring,
+                        Err(_) => return Err(rocket),
+                    }
+                },
+            };
+            rocket = rocket.manage(keyring);
+        }
+        // Same goes for the accounts, they might be using their own store.
+        if rocket.state::<ManagedStore>().is_none() {
+            rocket = Self::manage_account_db(rocket, &config).await?;
+        }
+
+        if rocket.state::<ManagedMailer>().is_none() {
+            let mailer = match mail::from_backend(&config.mail) {
+                Ok(mailer) => mailer,
+                Err(e) => {
+                    error!("Mailer is misconfigured: {}", e);
+                    return Err(rocket);
+                }
+            };
+            if let MailBackend::Memory = config.mail {
+                warn!("Emails are only kept in memory, no one will get them.");
+            }
+            rocket = rocket.manage(mailer);
+        }
+
+        if rocket.state::<ManagedListener>().is_none() {
+            rocket = rocket.manage::<ManagedListener>(Arc::new(LogListener));
+        }
+
+        if rocket.state::<Hasher>().is_none() {
+            let hasher = match Hasher::from_config(&config) {
+                Ok(hasher) => hasher,
+                Err(e) => {
+                    error!("{}", e);
+                    return Err(rocket);
+                }
+            };
+            rocket = rocket.manage(hasher);
+        }
+
+        let routes = config.routes
+            .iter()
+            .flat_map(|r| r.routes())
+            .collect::<Vec<_>>();
+
+        Ok(rocket
+            .mount(config.base_path.as_str(), routes)
+            .register(config.base_path.as_str(), catchers![pages::unauthorized, pages::internal_error])
+            .manage(config))
+    }
+}
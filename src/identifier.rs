@@ -0,0 +1,36 @@
+use unicode_normalization::UnicodeNormalization;
+
+/// The form logins are matched on, so `Alice@Example.com`, ` alice@example.com`
+/// and `ａｌｉｃｅ@EXAMPLE.com` are all the same account.
+///
+/// Whitespace is trimmed, the whole thing is NFKC normalised and case folded, and
+/// if it looks like an email the domain is converted to its ASCII (IDNA) form.
+/// ```
+/// use rust_authentication::identifier::normalize;
+///
+/// assert_eq!(normalize(" Alice@Example.COM "), "alice@example.com");
+/// assert_eq!(normalize("bob@Bücher.example"), "bob@xn--bcher-kva.example");
+/// ```
+pub fn normalize(login: &str) -> String {
+    // Normalised first, so a fullwidth `＠` still splits off the domain.
+    let login = login.nfkc().collect::<String>();
+    let login = login.trim();
+    match login.rsplit_once('@') {
+        Some((local, domain)) if !local.is_empty() && !domain.is_empty() => {
+            format!("{}@{}", fold(local), normalize_domain(domain))
+        },
+        _ => fold(login),
+    }
+}
+
+/// NFKC, then case fold. Folding can undo the normalisation, so it's done again after.
+fn fold(s: &str) -> String {
+    let normalized = s.nfkc().collect::<String>();
+    caseless::default_case_fold_str(&normalized).nfkc().collect()
+}
+
+/// Domains that aren't valid IDNs are still folded, so they at least match themselves.
+fn normalize_domain(domain: &str) -> String {
+    let domain = fold(domain);
+    idna::domain_to_ascii(&domain).unwrap_or(domain)
+}
@@ -1,5 +1,5 @@
 #![feature(trait_alias)]
-#![feature(io_error_more)]
+#![cfg_attr(feature = "sqlite", feature(io_error_more))]
 
 #![deny(clippy::unwrap_used)]
 #![deny(clippy::todo)]
@@ -12,30 +12,50 @@ mod test;
 mod db;
 mod schema;
 mod auth;
+mod fairing;
 
+pub mod config;
+pub mod events;
+pub mod identifier;
+pub mod mail;
 pub mod pages;
+pub mod password;
+pub mod store;
+pub mod validation;
 
 pub use auth::authentication::Session;
+pub use auth::user::{AuthUser, AuthUserError, LoadError, UserLoader};
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+pub use db::AccountDb;
+pub use db::{Account, AccountExport, AccountStoreError, ConnectionError, ExportedSession, LoginCollision, NewAccount};
+pub use fairing::AuthFairing;
 
-#[cfg(not(feature = "redis"))]
 use std::collections::HashMap;
-#[cfg(not(feature = "redis"))]
 use auth::authentication::Uuid;
 
-use crate::auth::keyring::Keyring;
+use crate::auth::keyring::{Keyring, SessionData, SessionStore};
+use crate::config::SessionBackend;
 use rocket::tokio::sync::RwLock;
 
-#[cfg(not(feature = "redis"))]
-pub type ManagedState = RwLock<Keyring<HashMap<Uuid, String>>>;
-
-#[cfg(feature = "redis")]
-pub type ManagedState = RwLock<Keyring<redis::Connection>>;
+pub type ManagedState = RwLock<Keyring<dyn SessionStore>>;
 
 /// Generate a Keyring to be used by your Rocket instance.
-pub fn get_state() -> ManagedState {
-    #[cfg(feature = "redis")]
-    return RwLock::new(Keyring { ring: Box::new(db::redis_connect().unwrap()) } );
+/// Fails if the session backend can't be reached.
+pub fn get_state(backend: &SessionBackend) -> Result<ManagedState, ConnectionError> {
+    let ring: Box<dyn SessionStore> = match backend {
+        SessionBackend::Memory => Box::new(HashMap::<Uuid, SessionData>::new()),
+        #[cfg(feature = "redis")]
+        SessionBackend::Redis { url } => Box::new(db::redis_connect(url.as_deref())?),
+        #[cfg(not(feature = "redis"))]
+        SessionBackend::Redis { .. } => return Err(ConnectionError::NotCompiled("redis")),
+    };
+    Ok(RwLock::new(Keyring { ring }))
+}
 
-    #[cfg(not(feature = "redis"))]
-    return RwLock::new(Keyring { ring: Box::new(HashMap::<Uuid, String>::new()) } );
+/// Seconds since the unix epoch.
+pub(crate) fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
 }
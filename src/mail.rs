@@ -0,0 +1,189 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use rocket::tokio::fs;
+use tracing::*;
+
+use crate::config::MailBackend;
+
+/// What Rocket manages so the routes can send email.
+/// [`crate::AuthFairing`] uses the configured [`MailBackend`], unless you are
+/// already managing one of these yourself. It's an `Arc` so emails can be
+/// sent after the response has gone out.
+pub type ManagedMailer = Arc<dyn Mailer>;
+
+/// An email to one user, in plain text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Sends the emails, ex: password reset links. Implement this to send
+/// them through your own provider.
+#[rocket::async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> Result<(), MailError>;
+}
+
+/// Make the mailer that was picked in the config.
+pub fn from_backend(backend: &MailBackend) -> Result<ManagedMailer, MailError> {
+    Ok(match backend {
+        MailBackend::Memory => Arc::new(MemoryMailer::new()),
+        MailBackend::File { dir } => Arc::new(FileMailer::new(dir)),
+        #[cfg(feature = "smtp")]
+        MailBackend::Smtp(config) => Arc::new(SmtpMailer::new(config)?),
+        #[cfg(not(feature = "smtp"))]
+        MailBackend::Smtp(_) => return Err(MailError::NotCompiled("smtp")),
+    })
+}
+
+/// Keeps every email it is given, mostly useful for tests.
+///
+/// Clones share the same outbox, so keep one around to read what got sent:
+/// ```
+/// use std::sync::Arc;
+/// use rust_authentication::mail::{ManagedMailer, MemoryMailer};
+///
+/// let mailer = MemoryMailer::new();
+/// let rocket = rocket::build().manage::<ManagedMailer>(Arc::new(mailer.clone()));
+/// assert!(mailer.sent().is_empty());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MemoryMailer {
+    outbox: Arc<Mutex<Vec<Email>>>,
+}
+
+impl MemoryMailer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Everything sent so far, oldest first.
+    pub fn sent(&self) -> Vec<Email> {
+        match self.outbox.lock() {
+            Ok(outbox) => outbox.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Mailer for MemoryMailer {
+    async fn send(&self, email: Email) -> Result<(), MailError> {
+        match self.outbox.lock() {
+            Ok(mut outbox) => outbox.push(email),
+            Err(poisoned) => poisoned.into_inner().push(email),
+        }
+        Ok(())
+    }
+}
+
+/// Writes each email to its own file in a directory, instead of sending it.
+/// Handy while developing, the links can be copied right out of them.
+#[derive(Debug, Clone)]
+pub struct FileMailer {
+    dir: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+#[rocket::async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: Email) -> Result<(), MailError> {
+        fs::create_dir_all(&self.dir).await?;
+
+        let path = self.dir.join(format!("{}-{}.eml", crate::now(), uuid::Uuid::new_v4()));
+        let contents = format!("To: {}\r\nSubject: {}\r\n\r\n{}\r\n", email.to, email.subject, email.body);
+        fs::write(&path, contents).await?;
+        debug!("Wrote email for '{}' to {}", email.to, path.display());
+        Ok(())
+    }
+}
+
+/// Sends email through an SMTP relay, needs the `smtp` feature.
+#[cfg(feature = "smtp")]
+pub struct SmtpMailer {
+    from: lettre::message::Mailbox,
+    transport: lettre::AsyncSmtpTransport<lettre::Tokio1Executor>,
+}
+
+#[cfg(feature = "smtp")]
+impl SmtpMailer {
+    /// No connection is made until the first email gets sent.
+    pub fn new(config: &crate::config::SmtpConfig) -> Result<Self, MailError> {
+        use lettre::{transport::smtp::authentication::Credentials, AsyncSmtpTransport, Tokio1Executor};
+
+        let from = config.from.parse().map_err(|e| MailError::Address(format!("{}: {e}", config.from)))?;
+
+        let mut builder = match config.starttls {
+            true => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host),
+            false => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host),
+        }
+        .map_err(|e| MailError::Smtp(e.to_string()))?;
+
+        if let Some(port) = config.port {
+            builder = builder.port(port);
+        }
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            builder = builder.credentials(Credentials::new(username.to_owned(), password.to_owned()));
+        }
+        Ok(Self { from, transport: builder.build() })
+    }
+}
+
+#[cfg(feature = "smtp")]
+#[rocket::async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: Email) -> Result<(), MailError> {
+        use lettre::AsyncTransport;
+
+        let to = email.to.parse().map_err(|e| MailError::Address(format!("{}: {e}", email.to)))?;
+        let message = lettre::Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(email.subject)
+            .body(email.body)
+            .map_err(|e| MailError::Smtp(e.to_string()))?;
+
+        self.transport.send(message).await.map_err(|e| MailError::Smtp(e.to_string()))?;
+        Ok(())
+    }
+}
+
+/// Why an email couldn't be sent.
+#[derive(Debug)]
+pub enum MailError {
+    /// The mailer was picked, but its feature wasn't enabled.
+    NotCompiled(&'static str),
+    /// An address couldn't be understood.
+    Address(String),
+    /// Writing the email out failed.
+    Io(std::io::Error),
+    /// The SMTP relay wouldn't take it.
+    Smtp(String),
+}
+
+impl std::fmt::Display for MailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MailError::NotCompiled(name)    => write!(f, "The '{name}' feature isn't enabled"),
+            MailError::Address(e)           => write!(f, "Invalid email address {e}"),
+            MailError::Io(e)                => write!(f, "Couldn't write the email: {e}"),
+            MailError::Smtp(e)              => write!(f, "Couldn't send the email: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for MailError {}
+
+impl From<std::io::Error> for MailError {
+    fn from(e: std::io::Error) -> Self {
+        MailError::Io(e)
+    }
+}
@@ -1,7 +1,18 @@
-use rocket::{get, response::status, http::{Cookie, CookieJar, Status}, State, post, serde::json::Json};
+use std::sync::Arc;
+
+use rocket::{catch, delete, get, response::status, http::{Cookie, CookieJar, Status}, State, post, serde::json::Json};
+use rocket::data::{Data, ToByteUnit};
 
 use crate::auth::authentication::{Session, SESSION_COOKIE_ID};
-use crate::db::{NewAccount, Account};
+use crate::db::{NewAccount, Account, AccountExport, AccountStoreError};
+use crate::config::AuthConfig;
+use crate::events::{AuthEvent, ManagedListener};
+use crate::mail::{Email, ManagedMailer};
+use crate::password::{self, Hasher, Strength};
+use crate::store::{ManagedStore, RedeemedToken, TokenPurpose};
+use crate::validation::ValidationErrors;
+use serde::Deserialize;
+use tracing::*;
 
 /// Realistically, any path requiring `Session` with do the same login attempts.
 #[get("/login")]
@@ -19,15 +30,515 @@ pub async fn logout(auth: Session, keyring: &State<crate::ManagedState>, jar: &C
 
 /// Really, this is just an example, as you will probably want some other account authentication
 /// method than just letting people create accounts willy-nilly.
+///
+/// The input is checked first against the password policy in the config,
+/// see [`NewAccount::validate`]. A link to verify
+/// their email gets sent to the new account.
 #[post("/create_account", data="<body>")]
-pub fn create_account(body: Json<NewAccount>) -> status::Custom<String> {
-    // TODO Cleanse the incoming data from SQL injections. (Diesel might do this already)
+pub async fn create_account(
+    body: Json<NewAccount<'_>>,
+    accounts: &State<ManagedStore>,
+    hasher: &State<Hasher>,
+    mailer: &State<ManagedMailer>,
+    config: &State<AuthConfig>,
+) -> Result<status::Custom<String>, ValidationErrors> {
+    body.validate(&config.password).await?;
     // TODO needs a good account approval method
-    match Account::new(body.0) {
-        Ok(_) => status::Custom(Status::Accepted, "Created".to_string()),
-        Err(e) => match e {
-            crate::db::AccountCreationError::UsernameAlreadyTaken => status::Custom(Status::Conflict, format!("'{}' is taken", body.name)),
-            _ => status::Custom(Status::InternalServerError, "Internal Error - Could not create account.".to_string())
+    let user = match Account::create(accounts.as_ref(), body.0, hasher).await {
+        Ok(user) => user,
+        Err(AccountStoreError::Conflict) => return Ok(status::Custom(Status::Conflict, format!("'{}' is taken", body.name))),
+        Err(e) => return Ok(store_error(e)),
+    };
+
+    // The account is there either way, they can ask for another link with `POST /verify/resend`.
+    let lifetime = config.verification.lifetime;
+    match Account::issue_token(accounts.as_ref(), user.id, TokenPurpose::VerifyEmail, lifetime, None).await {
+        Ok(token) => send(mailer, verification_email(user.login, &token, config)).await,
+        // the store doesn't do verification, there's no link to send
+        Err(AccountStoreError::Unsupported(_)) => {},
+        Err(e) => error!("Failed to issue verification token: {}", e),
+    }
+    Ok(status::Custom(Status::Accepted, "Created".to_string()))
+}
+
+fn verification_email(to: String, token: &str, config: &AuthConfig) -> Email {
+    Email {
+        to,
+        subject: "Verify your email".to_string(),
+        body: format!(
+            "Welcome! Follow this link within {} hours to verify your email:\n\n{}",
+            config.verification.lifetime / (60 * 60),
+            config.verification.with_token(token),
+        ),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ResendVerification<'a> {
+    email: &'a str,
+}
+
+/// Email a new verification link, for when the first one got lost or expired.
+/// Like [`request_password_reset`], it answers the same whether or not there's
+/// an unverified account with that email.
+#[post("/verify/resend", data="<body>")]
+pub async fn resend_verification(
+    body: Json<ResendVerification<'_>>,
+    accounts: &State<ManagedStore>,
+    mailer: &State<ManagedMailer>,
+    config: &State<AuthConfig>,
+) -> status::Custom<String> {
+    let sent = status::Custom(Status::Accepted, "If that account exists and isn't verified yet, a new link was sent to it.".to_string());
+
+    let user = match accounts.find_by_login(body.email).await {
+        Ok(Some(user)) if user.deleted_at.is_none() && !user.verified => user,
+        Ok(_) => return sent,
+        Err(e) => return store_error(e),
+    };
+    let lifetime = config.verification.lifetime;
+    match Account::issue_token(accounts.as_ref(), user.id, TokenPurpose::VerifyEmail, lifetime, None).await {
+        Ok(token) => send_later(mailer, verification_email(user.login, &token, config)),
+        Err(e) => error!("Couldn't make a verification token for account {}: {}", user.id, e),
+    }
+    sent
+}
+
+/// Where the link from the verification email leads.
+#[get("/verify?<token>")]
+pub async fn verify_email(token: &str, accounts: &State<ManagedStore>) -> status::Custom<String> {
+    let account_id = match Account::redeem_token(accounts.as_ref(), TokenPurpose::VerifyEmail, token).await {
+        Ok(Some(token)) => token.account_id,
+        Ok(None) => return status::Custom(Status::BadRequest, "The verification link is invalid or has expired.".to_string()),
+        Err(e) => return store_error(e),
+    };
+
+    match accounts.mark_verified(account_id).await {
+        Ok(()) => status::Custom(Status::Accepted, "Email verified".to_string()),
+        Err(e) => store_error(e),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PasswordChange<'a> {
+    /// Can be left out if they logged in recently.
+    #[serde(borrow)]
+    current_password: Option<&'a str>,
+    new_password: &'a str,
+}
+
+/// Change the password of whoever is logged in. They need to give their current
+/// password, unless they logged in within the last `fresh_session` seconds.
+/// All of their other sessions get logged out.
+#[post("/password", data="<body>")]
+pub async fn change_password(
+    auth: Session,
+    body: Json<PasswordChange<'_>>,
+    accounts: &State<ManagedStore>,
+    hasher: &State<Hasher>,
+    keyring: &State<crate::ManagedState>,
+    config: &State<AuthConfig>,
+) -> Result<status::Custom<String>, ValidationErrors> {
+    ValidationErrors::new()
+        .check_password("new_password", body.new_password, &config.password, Some(&auth.email))
+        .check_breached("new_password", body.new_password, &config.password)
+        .await
+        .into_result()?;
+    if let Err(failed) = reauthenticate(&auth, body.current_password, accounts, hasher, config).await {
+        return Ok(failed);
+    }
+
+    if let Err(e) = Account::change_password(accounts.as_ref(), auth.account_id, body.new_password, hasher).await {
+        return Ok(store_error(e));
+    }
+    keyring.write().await.logout_account(auth.account_id, Some(&auth));
+    Ok(status::Custom(Status::Accepted, "Password changed".to_string()))
+}
+
+#[derive(Deserialize)]
+pub struct StrengthCheck<'a> {
+    password: &'a str,
+    /// Who it's for, if they typed that in already.
+    #[serde(default)]
+    email: Option<&'a str>,
+}
+
+/// The most [`password_strength`] reads. Anyone can call it, so it gets a lot less
+/// than Rocket's default for JSON, but still plenty for a password and an email.
+const STRENGTH_LIMIT: usize = 16 * 1024;
+
+/// How good a password is, for signup forms to give feedback while it's being typed.
+/// Goes by the same password policy as [`create_account`], so `acceptable` tells if
+/// signing up with it would work. See [`password::estimate`].
+///
+/// Takes the same JSON as [`StrengthCheck`], but only up to 16 KiB of it (413 otherwise).
+#[post("/password/strength", data="<body>")]
+pub async fn password_strength(body: Data<'_>, config: &State<AuthConfig>) -> Result<Json<Strength>, Status> {
+    let body = body.open(STRENGTH_LIMIT.bytes()).into_string().await.map_err(|_| Status::BadRequest)?;
+    if !body.is_complete() {
+        return Err(Status::PayloadTooLarge);
+    }
+    let body = rocket::serde::json::from_str::<StrengthCheck>(&body).map_err(|_| Status::UnprocessableEntity)?;
+
+    let mut strength = password::estimate(&config.password, body.password, body.email);
+    if strength.acceptable {
+        let breached = ValidationErrors::new()
+            .check_breached("password", body.password, &config.password)
+            .await;
+        if !breached.is_empty() {
+            strength.breached();
+        }
+    }
+    Ok(Json(strength))
+}
+
+#[derive(Deserialize)]
+pub struct ResetRequest<'a> {
+    email: &'a str,
+}
+
+/// Email a password reset link to the account. Always says it was sent, and
+/// sends it after responding, so this can't be used to find out who has an account.
+#[post("/password/forgot", data="<body>")]
+pub async fn request_password_reset(
+    body: Json<ResetRequest<'_>>,
+    accounts: &State<ManagedStore>,
+    mailer: &State<ManagedMailer>,
+    config: &State<AuthConfig>,
+) -> status::Custom<String> {
+    let sent = status::Custom(Status::Accepted, "If that account exists, a reset link was sent to it.".to_string());
+
+    let user = match accounts.find_by_login(body.email).await {
+        Ok(Some(user)) if user.deleted_at.is_none() => user,
+        Ok(_) => return sent,
+        Err(e) => return store_error(e),
+    };
+    let lifetime = config.password_reset.lifetime;
+    let token = match Account::issue_token(accounts.as_ref(), user.id, TokenPurpose::PasswordReset, lifetime, None).await {
+        Ok(token) => token,
+        Err(e) => {
+            // answering any differently would give away that the account exists
+            error!("Couldn't make a password reset token for account {}: {}", user.id, e);
+            return sent;
+        },
+    };
+
+    let email = Email {
+        to: user.login,
+        subject: "Reset your password".to_string(),
+        body: format!(
+            "Someone asked to reset the password of this account. If it was you, follow this link within {} minutes:\n\n{}\n\nOtherwise, you can ignore this email.",
+            lifetime / 60,
+            config.password_reset.with_token(&token),
+        ),
+    };
+    send_later(mailer, email);
+    sent
+}
+
+#[derive(Deserialize)]
+pub struct PasswordReset<'a> {
+    token: &'a str,
+    new_password: &'a str,
+}
+
+/// Set a new password with the token from a reset email. The token can only
+/// be used once, and every session of the account gets logged out.
+#[post("/password/reset", data="<body>")]
+pub async fn reset_password(
+    body: Json<PasswordReset<'_>>,
+    accounts: &State<ManagedStore>,
+    hasher: &State<Hasher>,
+    keyring: &State<crate::ManagedState>,
+    config: &State<AuthConfig>,
+) -> Result<status::Custom<String>, ValidationErrors> {
+    // Checked before the token is used, so a bad password doesn't use it up. Whose
+    // account it is comes from the token, so it's only looked at for now.
+    let email = match Account::peek_token(accounts.as_ref(), TokenPurpose::PasswordReset, body.token).await {
+        Ok(Some(token)) => match accounts.find_by_id(token.account_id).await {
+            Ok(user) => user.map(|user| user.login),
+            Err(e) => return Ok(store_error(e)),
+        },
+        // a bad token gets turned away once it's used below
+        Ok(None) | Err(AccountStoreError::Unsupported(_)) => None,
+        Err(e) => return Ok(store_error(e)),
+    };
+    ValidationErrors::new()
+        .check_password("new_password", body.new_password, &config.password, email.as_deref())
+        .check_breached("new_password", body.new_password, &config.password)
+        .await
+        .into_result()?;
+    let account_id = match Account::redeem_token(accounts.as_ref(), TokenPurpose::PasswordReset, body.token).await {
+        Ok(Some(token)) => token.account_id,
+        Ok(None) => return Ok(status::Custom(Status::BadRequest, "The reset link is invalid or has expired.".to_string())),
+        Err(e) => return Ok(store_error(e)),
+    };
+
+    if let Err(e) = Account::change_password(accounts.as_ref(), account_id, body.new_password, hasher).await {
+        return Ok(store_error(e));
+    }
+    keyring.write().await.logout_account(account_id, None);
+    Ok(status::Custom(Status::Accepted, "Password changed".to_string()))
+}
+
+#[derive(Deserialize)]
+pub struct EmailChange<'a> {
+    /// Can be left out if they logged in recently.
+    #[serde(borrow)]
+    current_password: Option<&'a str>,
+    new_email: &'a str,
+}
+
+/// Start changing the email of whoever is logged in. Nothing changes until they
+/// follow the link sent to the new address, see [`confirm_email_change`].
+/// Like changing the password, this needs their current password or a fresh session.
+///
+/// While the last change can still be reverted it can't be changed again, so the
+/// revert link always goes to the address the account had before.
+#[post("/email", data="<body>")]
+pub async fn change_email(
+    auth: Session,
+    body: Json<EmailChange<'_>>,
+    accounts: &State<ManagedStore>,
+    hasher: &State<Hasher>,
+    mailer: &State<ManagedMailer>,
+    config: &State<AuthConfig>,
+) -> Result<status::Custom<String>, ValidationErrors> {
+    ValidationErrors::new().check_email("new_email", body.new_email).into_result()?;
+    if let Err(failed) = reauthenticate(&auth, body.current_password, accounts, hasher, config).await {
+        return Ok(failed);
+    }
+    match Account::has_token(accounts.as_ref(), auth.account_id, TokenPurpose::RevertEmail).await {
+        Ok(false) => {},
+        Ok(true) => return Ok(status::Custom(Status::Conflict, "The last email change can still be reverted, it can be changed again once that link expires.".to_string())),
+        // without tokens there's no change to revert, and no link to send either
+        Err(AccountStoreError::Unsupported(_)) => {},
+        Err(e) => return Ok(store_error(e)),
+    }
+    match accounts.find_by_login(body.new_email).await {
+        Ok(None) => {},
+        Ok(Some(_)) => return Ok(status::Custom(Status::Conflict, format!("'{}' is taken", body.new_email))),
+        Err(e) => return Ok(store_error(e)),
+    }
+
+    let lifetime = config.email_change.lifetime;
+    let new_email = body.new_email.trim().to_owned();
+    let token = match Account::issue_token(accounts.as_ref(), auth.account_id, TokenPurpose::ChangeEmail, lifetime, Some(new_email.clone())).await {
+        Ok(token) => token,
+        Err(e) => return Ok(store_error(e)),
+    };
+
+    send(mailer, Email {
+        to: new_email,
+        subject: "Confirm your new email".to_string(),
+        body: format!(
+            "Follow this link within {} hours to start using this address for your account:\n\n{}",
+            lifetime / (60 * 60),
+            config.email_change.with_token(&token),
+        ),
+    }).await;
+    Ok(status::Custom(Status::Accepted, "A confirmation link was sent to the new address.".to_string()))
+}
+
+/// Where the link sent to the new address leads. Switches the account over to it,
+/// and sends the old address a link to switch it back, see [`revert_email_change`].
+#[get("/email/confirm?<token>")]
+pub async fn confirm_email_change(
+    token: &str,
+    accounts: &State<ManagedStore>,
+    keyring: &State<crate::ManagedState>,
+    mailer: &State<ManagedMailer>,
+    config: &State<AuthConfig>,
+) -> status::Custom<String> {
+    let (account_id, new_email) = match Account::redeem_token(accounts.as_ref(), TokenPurpose::ChangeEmail, token).await {
+        Ok(Some(RedeemedToken { account_id, data: Some(new_email) })) => (account_id, new_email),
+        Ok(_) => return status::Custom(Status::BadRequest, "The confirmation link is invalid or has expired.".to_string()),
+        Err(e) => return store_error(e),
+    };
+    let old_email = match accounts.find_by_id(account_id).await {
+        Ok(Some(user)) => user.login,
+        Ok(None) => return status::Custom(Status::BadRequest, "The account no longer exists.".to_string()),
+        Err(e) => return store_error(e),
+    };
+
+    match accounts.update_login(account_id, &new_email).await {
+        Ok(()) => {},
+        // someone else took it after the link was sent
+        Err(AccountStoreError::Conflict) => return status::Custom(Status::Conflict, format!("'{}' is taken", new_email)),
+        Err(e) => return store_error(e),
+    }
+    // following the link proved it's theirs
+    match accounts.mark_verified(account_id).await {
+        Ok(()) | Err(AccountStoreError::Unsupported(_)) => {},
+        Err(e) => return store_error(e),
+    }
+    keyring.write().await.update_email(account_id, &new_email);
+
+    let lifetime = config.email_revert.lifetime;
+    match Account::issue_token(accounts.as_ref(), account_id, TokenPurpose::RevertEmail, lifetime, Some(old_email.clone())).await {
+        Ok(token) => send(mailer, Email {
+            to: old_email,
+            subject: "Your email was changed".to_string(),
+            body: format!(
+                "The email of your account was changed to {}. If this wasn't you, follow this link within {} days to change it back:\n\n{}",
+                new_email,
+                lifetime / (24 * 60 * 60),
+                config.email_revert.with_token(&token),
+            ),
+        }).await,
+        Err(e) => error!("Failed to issue email revert token: {}", e),
+    }
+    status::Custom(Status::Accepted, "Email changed".to_string())
+}
+
+/// Where the link sent to the old address leads. Switches the account back to it,
+/// and logs out every session, as whoever changed it shouldn't stay logged in.
+#[get("/email/revert?<token>")]
+pub async fn revert_email_change(
+    token: &str,
+    accounts: &State<ManagedStore>,
+    keyring: &State<crate::ManagedState>,
+) -> status::Custom<String> {
+    let (account_id, old_email) = match Account::redeem_token(accounts.as_ref(), TokenPurpose::RevertEmail, token).await {
+        Ok(Some(RedeemedToken { account_id, data: Some(old_email) })) => (account_id, old_email),
+        Ok(_) => return status::Custom(Status::BadRequest, "The revert link is invalid or has expired.".to_string()),
+        Err(e) => return store_error(e),
+    };
+
+    match accounts.update_login(account_id, &old_email).await {
+        Ok(()) => {},
+        Err(AccountStoreError::Conflict) => return status::Custom(Status::Conflict, format!("'{}' is taken", old_email)),
+        Err(e) => return store_error(e),
+    }
+    // they got the link, so it's still theirs
+    match accounts.mark_verified(account_id).await {
+        Ok(()) | Err(AccountStoreError::Unsupported(_)) => {},
+        Err(e) => return store_error(e),
+    }
+    keyring.write().await.logout_account(account_id, None);
+    status::Custom(Status::Accepted, "Email changed back, all sessions were logged out.".to_string())
+}
+
+#[derive(Deserialize)]
+pub struct AccountDeletion<'a> {
+    /// Can be left out if they logged in recently.
+    #[serde(borrow)]
+    current_password: Option<&'a str>,
+}
+
+/// Delete the account of whoever is logged in, logging out all of their sessions.
+/// Like changing the password, this needs their current password or a fresh session.
+///
+/// The account is kept for `deletion_grace` seconds before being purged, a listener
+/// is told about both through [`AuthEvent`]s.
+#[delete("/account", data="<body>")]
+#[allow(clippy::too_many_arguments)] // they're all request guards
+pub async fn delete_account(
+    auth: Session,
+    body: Json<AccountDeletion<'_>>,
+    accounts: &State<ManagedStore>,
+    hasher: &State<Hasher>,
+    keyring: &State<crate::ManagedState>,
+    listener: &State<ManagedListener>,
+    config: &State<AuthConfig>,
+    jar: &CookieJar<'_>,
+) -> status::Custom<String> {
+    if let Err(failed) = reauthenticate(&auth, body.current_password, accounts, hasher, config).await {
+        return failed;
+    }
+
+    let purge_at = match Account::delete(accounts.as_ref(), auth.account_id, config.deletion_grace).await {
+        Ok(purge_at) => purge_at,
+        Err(e) => return store_error(e),
+    };
+    keyring.write().await.logout_account(auth.account_id, None);
+    jar.remove_private(Cookie::from(SESSION_COOKIE_ID));
+
+    listener.on_event(&AuthEvent::AccountDeleted {
+        account_id: auth.account_id,
+        email: auth.email.clone(),
+        purge_at,
+    }).await;
+    // Without a grace period it's purged right away, otherwise this catches up on others.
+    match Account::purge_deleted(accounts.as_ref(), config.deletion_grace, listener.as_ref()).await {
+        Ok(_) | Err(AccountStoreError::Unsupported(_)) => {},
+        Err(e) => error!("Failed to purge deleted accounts: {}", e),
+    }
+    status::Custom(Status::Accepted, "Account deleted".to_string())
+}
+
+/// Everything that is known about whoever is logged in, as JSON.
+/// See [`Account::export`]. This doesn't include linked identities, the crate has none.
+#[get("/account/export")]
+pub async fn export_account(
+    auth: Session,
+    accounts: &State<ManagedStore>,
+    keyring: &State<crate::ManagedState>,
+) -> Result<Json<AccountExport>, status::Custom<String>> {
+    Account::export(accounts.as_ref(), keyring, auth.account_id, Some(&auth))
+        .await
+        .map(Json)
+        .map_err(store_error)
+}
+
+/// Make sure it's really them before something sensitive: either they give their
+/// `current_password`, or they logged in within the last `fresh_session` seconds.
+async fn reauthenticate(
+    auth: &Session,
+    current_password: Option<&str>,
+    accounts: &ManagedStore,
+    hasher: &Hasher,
+    config: &AuthConfig,
+) -> Result<(), status::Custom<String>> {
+    let verified = match current_password {
+        Some(current) => Account::check_password(accounts.as_ref(), auth.account_id, current, hasher)
+            .await
+            .map_err(store_error)?,
+        None => auth.is_fresh(config.fresh_session),
+    };
+    match verified {
+        true => Ok(()),
+        false => Err(status::Custom(Status::Unauthorized, "The current password is wrong or missing.".to_string())),
+    }
+}
+
+/// Failing to send an email doesn't fail the request, it just gets logged.
+async fn send(mailer: &ManagedMailer, email: Email) {
+    let to = email.to.clone();
+    if let Err(e) = mailer.send(email).await {
+        error!("Failed to send email to '{}': {}", to, e);
+    }
+}
+
+/// Send it in the background, so how long the request took doesn't say whether
+/// an email was sent.
+fn send_later(mailer: &ManagedMailer, email: Email) {
+    let mailer = Arc::clone(mailer);
+    rocket::tokio::spawn(async move { send(&mailer, email).await });
+}
+
+/// Tell the client the account database failed, without telling them why.
+/// Something the store doesn't do at all is a 501.
+fn store_error(e: AccountStoreError) -> status::Custom<String> {
+    match e {
+        AccountStoreError::Unavailable(_) => status::Custom(Status::ServiceUnavailable, "Could not reach the account database.".to_string()),
+        AccountStoreError::Unsupported(what) => {
+            warn!("The account store doesn't support {}", what);
+            status::Custom(Status::NotImplemented, "Not supported by the account store.".to_string())
+        },
+        e => {
+            error!("Account database error: {}", e);
+            status::Custom(Status::InternalServerError, "Internal Error".to_string())
         },
     }
 }
+
+/// What the client gets when a [`Session`] couldn't be made for them.
+#[catch(401)]
+pub fn unauthorized() -> &'static str {
+    "Not logged in"
+}
+
+#[catch(500)]
+pub fn internal_error() -> &'static str {
+    "Internal Error"
+}
@@ -0,0 +1,747 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+
+use argon2::{
+    password_hash::{self, rand_core::OsRng, PasswordHash, PasswordHashString, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, KeyId, Params, ParamsBuilder, Version,
+};
+use tracing::*;
+
+use crate::config::{AuthConfig, ConfigError, HashingConfig, PasswordPolicy, PepperConfig, PepperSource};
+use crate::validation::FieldError;
+
+/// Hashes and verifies passwords, with the Argon2 costs and peppers from the config.
+/// [`crate::AuthFairing`] manages one, unless you are already managing your own.
+///
+/// New hashes get the current pepper's key id in their PHC string (`keyid=...`),
+/// which is how verifying knows which pepper to use.
+/// ```
+/// use rust_authentication::password::Hasher;
+///
+/// let hasher = Hasher::default().with_pepper("2026", "not a very good secret").unwrap();
+/// let hash = hasher.hash("hunter2").unwrap();
+/// assert!(hasher.verify("hunter2", hash.as_str()));
+/// // without the pepper it's no use
+/// assert!(!Hasher::default().verify("hunter2", hash.as_str()));
+/// ```
+///
+/// Hashes in other formats can be verified too, for accounts that were imported
+/// from somewhere else (see [`crate::Account::import`]). bcrypt, scrypt and
+/// PBKDF2 are known out of the box, more can be added with [`Self::with_legacy`].
+/// They all count as outdated, so they get replaced with Argon2id hashes as soon
+/// as their accounts log in.
+#[derive(Clone)]
+pub struct Hasher {
+    costs: HashingConfig,
+    /// The key id of the pepper new hashes get.
+    current: Option<String>,
+    peppers: Arc<HashMap<String, Vec<u8>>>,
+    legacy: Vec<Arc<dyn LegacyHash>>,
+}
+
+impl Default for Hasher {
+    fn default() -> Self {
+        Self {
+            costs: HashingConfig::default(),
+            current: None,
+            peppers: Arc::default(),
+            legacy: vec![Arc::new(Bcrypt), Arc::new(Pbkdf2), Arc::new(Scrypt)],
+        }
+    }
+}
+
+impl fmt::Debug for Hasher {
+    /// Leaves out the peppers themselves.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hasher")
+            .field("costs", &self.costs)
+            .field("current", &self.current)
+            .field("peppers", &self.peppers.keys().collect::<Vec<_>>())
+            .field("legacy", &self.legacy.iter().map(|legacy| legacy.name()).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Hasher {
+    /// Without any pepper.
+    pub fn new(costs: HashingConfig) -> Result<Self, ConfigError> {
+        costs.hasher().map_err(ConfigError::InvalidHashing)?;
+        Ok(Self { costs, ..Self::default() })
+    }
+
+    /// With the costs and peppers in the config, reading any pepper files.
+    pub fn from_config(config: &AuthConfig) -> Result<Self, ConfigError> {
+        let mut hasher = Self::new(config.hashing)?;
+        let mut peppers = HashMap::new();
+        for (key_id, source) in &config.pepper.keys {
+            let secret = match source {
+                PepperSource::Secret { secret } => secret.as_bytes().to_vec(),
+                PepperSource::File { file } => {
+                    let mut secret = std::fs::read(file)
+                        .map_err(|e| ConfigError::InvalidPepper(format!("couldn't read '{file}': {e}")))?;
+                    while secret.last().is_some_and(|b| *b == b'\n' || *b == b'\r') {
+                        secret.pop();
+                    }
+                    if secret.len() < PepperConfig::MIN_SECRET {
+                        return Err(ConfigError::InvalidPepper(format!("'{file}' has to be at least {} bytes", PepperConfig::MIN_SECRET)));
+                    }
+                    secret
+                },
+            };
+            peppers.insert(key_id.clone(), secret);
+        }
+        hasher.current = config.pepper.current.clone();
+        hasher.peppers = Arc::new(peppers);
+        Ok(hasher)
+    }
+
+    /// Add a pepper, and use it for new hashes from now on.
+    pub fn with_pepper(self, key_id: &str, secret: impl Into<Vec<u8>>) -> Result<Self, ConfigError> {
+        let mut hasher = self.with_old_pepper(key_id, secret)?;
+        hasher.current = Some(key_id.to_owned());
+        Ok(hasher)
+    }
+
+    /// Add a pepper that's only used for verifying hashes that were made with it.
+    pub fn with_old_pepper(mut self, key_id: &str, secret: impl Into<Vec<u8>>) -> Result<Self, ConfigError> {
+        if key_id.is_empty() || key_id.len() > PepperConfig::MAX_KEY_ID {
+            return Err(ConfigError::InvalidPepper(format!("the key id '{key_id}' has to be 1 to {} bytes", PepperConfig::MAX_KEY_ID)));
+        }
+        Arc::make_mut(&mut self.peppers).insert(key_id.to_owned(), secret.into());
+        Ok(self)
+    }
+
+    /// Also verify hashes in another format. They're tried in the order they were
+    /// added, after the built in ones.
+    pub fn with_legacy(mut self, legacy: impl LegacyHash + 'static) -> Self {
+        self.legacy.push(Arc::new(legacy));
+        self
+    }
+
+    pub fn costs(&self) -> &HashingConfig {
+        &self.costs
+    }
+
+    /// Hash a password with Argon2id, with the current pepper if there is one.
+    /// Only fails if Argon2 doesn't take the costs.
+    pub fn hash(&self, password: &str) -> Result<PasswordHashString, password_hash::Error> {
+        let mut params = ParamsBuilder::new();
+        params
+            .m_cost(self.costs.memory_cost)
+            .t_cost(self.costs.iterations)
+            .p_cost(self.costs.parallelism);
+        let argon = match self.current_pepper() {
+            Some((key_id, secret)) => {
+                params.keyid(KeyId::new(key_id.as_bytes())?);
+                Argon2::new_with_secret(secret, Algorithm::Argon2id, Version::V0x13, params.build()?)?
+            },
+            None => Argon2::new(Algorithm::Argon2id, Version::V0x13, params.build()?),
+        };
+
+        let salt = SaltString::generate(OsRng);
+        Ok(argon.hash_password(password.as_bytes(), &salt)?.serialize())
+    }
+
+    /// Check a password against the hash that was stored for it, with whichever
+    /// pepper its key id says. If that pepper isn't known anymore it can't match.
+    /// Hashes that aren't Argon2 are handed to the legacy format they're in.
+    pub fn verify(&self, password: &str, stored_hash: &str) -> bool {
+        if let Some(legacy) = self.legacy_format(stored_hash) {
+            return legacy.verify(password, stored_hash);
+        }
+        let hash = match PasswordHash::new(stored_hash) {
+            Ok(hash) => hash,
+            Err(e) => {
+                error!("A password hash isn't in any known format: {}", e);
+                return false;
+            },
+        };
+        let key_id = match Params::try_from(&hash) {
+            Ok(params) => params.keyid().to_vec(),
+            Err(_) => Vec::new(),
+        };
+        if key_id.is_empty() {
+            return Argon2::default().verify_password(password.as_bytes(), &hash).is_ok();
+        }
+
+        let secret = std::str::from_utf8(&key_id)
+            .ok()
+            .and_then(|key_id| self.peppers.get(key_id));
+        match secret.map(|secret| Argon2::new_with_secret(secret, Algorithm::default(), Version::default(), Params::default())) {
+            Some(Ok(argon)) => argon.verify_password(password.as_bytes(), &hash).is_ok(),
+            Some(Err(e)) => {
+                error!("Couldn't use the pepper for a hash: {}", e);
+                false
+            },
+            None => {
+                error!("A password hash has the pepper '{}', which isn't configured", String::from_utf8_lossy(&key_id));
+                false
+            },
+        }
+    }
+
+    /// Was the hash made some other way than [`Self::hash`] would make it now?
+    /// Either with another algorithm, other costs, or another pepper.
+    /// Anything that can't be read counts as outdated, as do all legacy formats.
+    pub fn needs_rehash(&self, stored_hash: &str) -> bool {
+        let Ok(hash) = PasswordHash::new(stored_hash) else {
+            return true;
+        };
+        if hash.algorithm != Algorithm::Argon2id.ident() || hash.version != Some(Version::V0x13.into()) {
+            return true;
+        }
+        let Ok(params) = Params::try_from(&hash) else {
+            return true;
+        };
+        let current = self.current_pepper().map(|(key_id, _)| key_id.as_bytes()).unwrap_or_default();
+        (params.m_cost(), params.t_cost(), params.p_cost()) != (self.costs.memory_cost, self.costs.iterations, self.costs.parallelism)
+            || params.keyid() != current
+    }
+
+    /// Can [`Self::verify`] check passwords against this? Either it's an Argon2
+    /// PHC string, or one of the legacy formats recognizes it.
+    pub fn can_verify(&self, stored_hash: &str) -> bool {
+        if self.legacy_format(stored_hash).is_some() {
+            return true;
+        }
+        PasswordHash::new(stored_hash).is_ok_and(|hash| Algorithm::try_from(hash.algorithm).is_ok())
+    }
+
+    fn legacy_format(&self, stored_hash: &str) -> Option<&dyn LegacyHash> {
+        self.legacy.iter().map(Arc::as_ref).find(|legacy| legacy.recognizes(stored_hash))
+    }
+
+    fn current_pepper(&self) -> Option<(&String, &Vec<u8>)> {
+        let key_id = self.current.as_ref()?;
+        self.peppers.get_key_value(key_id)
+    }
+}
+
+/// A password hash format that's only ever verified, never made. See [`Hasher::with_legacy`].
+/// ```
+/// use rust_authentication::password::{Hasher, LegacyHash};
+///
+/// /// Hashes of an old system that really shouldn't have been doing this.
+/// struct Reversed;
+///
+/// impl LegacyHash for Reversed {
+///     fn name(&self) -> &'static str {
+///         "reversed"
+///     }
+///
+///     fn recognizes(&self, stored_hash: &str) -> bool {
+///         stored_hash.starts_with("$reversed$")
+///     }
+///
+///     fn verify(&self, password: &str, stored_hash: &str) -> bool {
+///         stored_hash["$reversed$".len()..].chars().eq(password.chars().rev())
+///     }
+/// }
+///
+/// let hasher = Hasher::default().with_legacy(Reversed);
+/// assert!(hasher.verify("hunter2", "$reversed$2retnuh"));
+/// assert!(hasher.needs_rehash("$reversed$2retnuh"));
+/// ```
+pub trait LegacyHash: Send + Sync {
+    /// What it's called in the logs.
+    fn name(&self) -> &'static str;
+    /// Is the hash in this format? Only looks at its shape, usually its prefix.
+    fn recognizes(&self, stored_hash: &str) -> bool;
+    /// Does the password match? Anything malformed doesn't. This gets called off
+    /// of the async threads, so it can take its time.
+    fn verify(&self, password: &str, stored_hash: &str) -> bool;
+}
+
+/// bcrypt's modular crypt format, `$2b$<cost>$<salt and hash>`, along with the
+/// older `$2a$`, `$2x$` and `$2y$` variants.
+#[derive(Debug, Clone, Copy)]
+pub struct Bcrypt;
+
+impl LegacyHash for Bcrypt {
+    fn name(&self) -> &'static str {
+        "bcrypt"
+    }
+
+    /// The whole shape, as it's fixed: the variant, a two digit cost, then 22
+    /// characters of salt and 31 of hash.
+    fn recognizes(&self, stored_hash: &str) -> bool {
+        let Some(rest) = ["$2a$", "$2b$", "$2x$", "$2y$"].iter().find_map(|prefix| stored_hash.strip_prefix(prefix)) else {
+            return false;
+        };
+        match rest.split_once('$') {
+            Some((cost, salt_and_hash)) => {
+                cost.len() == 2
+                    && cost.bytes().all(|b| b.is_ascii_digit())
+                    && salt_and_hash.len() == 53
+                    && salt_and_hash.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'/')
+            },
+            None => false,
+        }
+    }
+
+    fn verify(&self, password: &str, stored_hash: &str) -> bool {
+        match bcrypt::verify(password, stored_hash) {
+            Ok(matches) => matches,
+            Err(e) => {
+                error!("Unreadable bcrypt hash: {}", e);
+                false
+            },
+        }
+    }
+}
+
+/// PBKDF2 PHC strings, `$pbkdf2-sha256$i=<rounds>,l=<length>$<salt>$<hash>`,
+/// or `$pbkdf2-sha512$...`.
+#[derive(Debug, Clone, Copy)]
+pub struct Pbkdf2;
+
+impl LegacyHash for Pbkdf2 {
+    fn name(&self) -> &'static str {
+        "pbkdf2"
+    }
+
+    fn recognizes(&self, stored_hash: &str) -> bool {
+        stored_hash.starts_with("$pbkdf2-sha256$") || stored_hash.starts_with("$pbkdf2-sha512$")
+    }
+
+    fn verify(&self, password: &str, stored_hash: &str) -> bool {
+        verify_phc(&pbkdf2::Pbkdf2, self.name(), password, stored_hash)
+    }
+}
+
+/// scrypt PHC strings, `$scrypt$ln=<log n>,r=<r>,p=<p>$<salt>$<hash>`.
+#[derive(Debug, Clone, Copy)]
+pub struct Scrypt;
+
+impl LegacyHash for Scrypt {
+    fn name(&self) -> &'static str {
+        "scrypt"
+    }
+
+    fn recognizes(&self, stored_hash: &str) -> bool {
+        stored_hash.starts_with("$scrypt$")
+    }
+
+    fn verify(&self, password: &str, stored_hash: &str) -> bool {
+        verify_phc(&scrypt::Scrypt, self.name(), password, stored_hash)
+    }
+}
+
+fn verify_phc(verifier: &dyn PasswordVerifier, name: &str, password: &str, stored_hash: &str) -> bool {
+    match PasswordHash::new(stored_hash) {
+        Ok(hash) => verifier.verify_password(password.as_bytes(), &hash).is_ok(),
+        Err(e) => {
+            error!("Unreadable {} hash: {}", name, e);
+            false
+        },
+    }
+}
+
+/// Everything about `password` that goes against the policy, apart from it being
+/// breached (see [`is_breached`]). Passing the account's `email` also checks the
+/// password doesn't contain it.
+/// ```
+/// use rust_authentication::{config::PasswordPolicy, password::violations, validation::FieldError};
+///
+/// let policy = PasswordPolicy { require_digit: true, ..PasswordPolicy::default() };
+/// assert_eq!(violations(&policy, "PASSWORD", None), [FieldError::MissingDigit, FieldError::Common]);
+/// assert_eq!(violations(&policy, "alice1980", Some("alice@example.com")), [FieldError::SimilarToEmail]);
+/// assert!(violations(&policy, "correct horse 1 battery", None).is_empty());
+/// ```
+pub fn violations(policy: &PasswordPolicy, password: &str, email: Option<&str>) -> Vec<FieldError> {
+    if password.is_empty() {
+        return vec![FieldError::Required];
+    }
+    let mut errors = Vec::new();
+
+    let length = password.chars().count();
+    if length < policy.min_length {
+        errors.push(FieldError::TooShort);
+    }
+    if length > policy.max_length {
+        errors.push(FieldError::TooLong);
+    }
+    if password.chars().any(char::is_control) {
+        errors.push(FieldError::InvalidCharacters);
+    }
+
+    let classes = [
+        (policy.require_lowercase, FieldError::MissingLowercase, char::is_lowercase as fn(char) -> bool),
+        (policy.require_uppercase, FieldError::MissingUppercase, char::is_uppercase),
+        (policy.require_digit, FieldError::MissingDigit, |c| c.is_numeric()),
+        (policy.require_symbol, FieldError::MissingSymbol, |c| !c.is_alphanumeric() && !c.is_whitespace()),
+    ];
+    for (required, error, is_class) in classes {
+        if required && !password.chars().any(is_class) {
+            errors.push(error);
+        }
+    }
+
+    let lowercase = password.to_lowercase();
+    if policy.deny_list.iter().any(|denied| denied.to_lowercase() == lowercase) {
+        errors.push(FieldError::Common);
+    }
+    if policy.reject_email && email.is_some_and(|email| contains_email(&lowercase, email)) {
+        errors.push(FieldError::SimilarToEmail);
+    }
+    errors
+}
+
+/// Whether the (lower case) password contains the email or its local part,
+/// or is a big chunk of the email itself.
+fn contains_email(password: &str, email: &str) -> bool {
+    let email = email.trim().to_lowercase();
+    let local = email.rsplit_once('@').map_or(email.as_str(), |(local, _)| local);
+    (local.chars().count() >= 3 && password.contains(local))
+        || (password.chars().count() >= 3 && email.contains(password))
+}
+
+/// How good a password is, as told to signup forms by [`crate::pages::password_strength`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Strength {
+    /// From 0 (guessed right away) to 4 (not going to be guessed). Always 0 if
+    /// the password goes against the policy.
+    pub score: u8,
+    /// Roughly how many guesses it would take, as bits.
+    pub entropy: f64,
+    /// Whether the policy lets it through, i.e. `errors` is empty.
+    pub acceptable: bool,
+    /// What the policy has against it, the same codes signup responds with.
+    pub errors: Vec<FieldError>,
+    /// What would make it better, for showing to whoever is typing it.
+    pub suggestions: Vec<&'static str>,
+}
+
+impl Strength {
+    /// It turned up in the policy's breached list.
+    pub(crate) fn breached(&mut self) {
+        self.score = 0;
+        self.acceptable = false;
+        self.errors.push(FieldError::Breached);
+        self.suggestions.insert(0, "This password has shown up in a data breach, pick another one.");
+    }
+}
+
+/// Something that makes a password easier to guess than its length suggests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pattern {
+    /// A common password or word, or one on the deny list. Also l33t spelled.
+    Common,
+    /// Keys next to each other, like `qwerty` or `asdf`.
+    KeyboardWalk,
+    /// Like `abc` or `4321`.
+    Sequence,
+    /// The same character over and over.
+    Repeat,
+}
+
+impl Pattern {
+    /// About how many bits a whole run of the pattern is worth.
+    fn entropy(self) -> f64 {
+        match self {
+            Pattern::Common => 11.0,
+            Pattern::KeyboardWalk | Pattern::Sequence => 5.0,
+            Pattern::Repeat => 3.0,
+        }
+    }
+
+    fn suggestion(self) -> &'static str {
+        match self {
+            Pattern::Common => "Avoid common words and passwords, even with symbols swapped in for letters.",
+            Pattern::KeyboardWalk => "Avoid rows of keys like 'qwerty' or 'asdf'.",
+            Pattern::Sequence => "Avoid sequences like 'abc' or '1234'.",
+            Pattern::Repeat => "Avoid repeating the same character.",
+        }
+    }
+}
+
+/// Words that are in every password cracking dictionary, on top of the policy's deny list.
+const COMMON_WORDS: &[&str] = &[
+    "password", "passwort", "qwerty", "letmein", "welcome", "admin", "login", "monkey",
+    "dragon", "master", "princess", "football", "baseball", "soccer", "hockey", "iloveyou",
+    "sunshine", "shadow", "superman", "batman", "trustno", "secret", "starwars", "hello",
+    "freedom", "whatever", "charlie", "michael", "jordan", "pokemon", "summer", "winter",
+    "flower", "cheese", "computer", "internet", "changeme", "default", "access", "test",
+];
+
+const KEYBOARD_ROWS: &[&str] = &["1234567890", "qwertyuiop", "asdfghjkl", "zxcvbnm"];
+
+/// Estimate how hard `password` is to guess, and whether the `policy` accepts it.
+/// The breached list isn't looked at, see [`is_breached`] for that. Anything longer
+/// than the policy's `max_length` is only told it's too long, without looking for
+/// patterns, as that takes a while on long input.
+/// ```
+/// use rust_authentication::{config::PasswordPolicy, password::estimate, validation::FieldError};
+///
+/// let policy = PasswordPolicy::default();
+/// assert_eq!(estimate(&policy, "qwerty123", None).score, 0);
+/// assert_eq!(estimate(&policy, "gravel sonnet wander 48", None).score, 4);
+/// assert!(!estimate(&policy, "short", None).acceptable);
+/// assert_eq!(estimate(&policy, &"a".repeat(1000), None).errors, [FieldError::TooLong]);
+/// ```
+pub fn estimate(policy: &PasswordPolicy, password: &str, email: Option<&str>) -> Strength {
+    if password.chars().count() > policy.max_length {
+        return Strength {
+            score: 0,
+            entropy: 0.0,
+            acceptable: false,
+            errors: vec![FieldError::TooLong],
+            suggestions: vec!["Use a shorter password."],
+        };
+    }
+    let errors = violations(policy, password, email);
+    let chars = password.to_lowercase().chars().collect::<Vec<_>>();
+    let mut covered = vec![false; chars.len()];
+    let mut found = Vec::new();
+
+    let mut words = COMMON_WORDS.iter().map(|word| word.to_string()).collect::<Vec<_>>();
+    words.extend(policy.deny_list.iter().map(|word| word.to_lowercase()));
+    let unleeted = chars.iter().map(|c| unleet(*c)).collect::<Vec<_>>();
+    for word in words.iter().map(|word| word.chars().collect::<Vec<_>>()).filter(|word| word.len() >= 3) {
+        for start in 0..chars.len().saturating_sub(word.len() - 1) {
+            let end = start + word.len();
+            if chars[start..end] == word[..] || unleeted[start..end] == word[..] {
+                mark(&mut covered, start..end, Pattern::Common, &mut found);
+            }
+        }
+    }
+    for (start, end, _) in runs(&chars, keyboard_step) {
+        if end - start >= 4 {
+            mark(&mut covered, start..end, Pattern::KeyboardWalk, &mut found);
+        }
+    }
+    for (start, end, step) in runs(&chars, |a, b| (a == b).then_some(0).or_else(|| sequence_step(a, b))) {
+        if end - start >= 3 {
+            let pattern = if step == 0 { Pattern::Repeat } else { Pattern::Sequence };
+            mark(&mut covered, start..end, pattern, &mut found);
+        }
+    }
+
+    let pool = pool_size(password);
+    let uncovered = covered.iter().filter(|c| !**c).count() as f64;
+    let entropy = uncovered * pool.log2() + found.iter().map(|pattern| pattern.entropy()).sum::<f64>();
+
+    let score = match entropy {
+        _ if !errors.is_empty() => 0,
+        e if e < 28.0 => 0,
+        e if e < 36.0 => 1,
+        e if e < 60.0 => 2,
+        e if e < 80.0 => 3,
+        _ => 4,
+    };
+
+    let mut suggestions = Vec::new();
+    for pattern in &found {
+        if !suggestions.contains(&pattern.suggestion()) {
+            suggestions.push(pattern.suggestion());
+        }
+    }
+    if score < 3 {
+        suggestions.push("Add another word or two. Uncommon words are better.");
+    }
+
+    Strength {
+        score,
+        entropy: (entropy * 10.0).round() / 10.0,
+        acceptable: errors.is_empty(),
+        errors,
+        suggestions,
+    }
+}
+
+/// Remember the pattern, unless its characters were already part of another one.
+fn mark(covered: &mut [bool], range: Range<usize>, pattern: Pattern, found: &mut Vec<Pattern>) {
+    if covered[range.clone()].iter().all(|c| *c) {
+        return;
+    }
+    covered[range].iter_mut().for_each(|c| *c = true);
+    found.push(pattern);
+}
+
+/// Stretches of `chars` where the `step` between neighbours is the same every time,
+/// as `(start, end, step)`.
+fn runs(chars: &[char], step: impl Fn(char, char) -> Option<i32>) -> Vec<(usize, usize, i32)> {
+    let mut runs = Vec::new();
+    let mut start = 0;
+    let mut current = None;
+    for i in 1..=chars.len() {
+        let next = chars.get(i).and_then(|c| step(chars[i - 1], *c));
+        match (current, next) {
+            (Some(delta), Some(next)) if delta == next => {},
+            (None, Some(_)) => {
+                start = i - 1;
+                current = next;
+            },
+            (Some(delta), _) => {
+                runs.push((start, i, delta));
+                start = i - 1;
+                current = next;
+            },
+            (None, None) => {},
+        }
+    }
+    runs
+}
+
+/// `1` or `-1` if `b` is the key right after or before `a` on the same row.
+fn keyboard_step(a: char, b: char) -> Option<i32> {
+    KEYBOARD_ROWS.iter().find_map(|row| {
+        let a = row.find(a)? as i32;
+        let b = row.find(b)? as i32;
+        matches!(b - a, 1 | -1).then_some(b - a)
+    })
+}
+
+/// `1` or `-1` if `b` is the letter or digit right after or before `a`.
+fn sequence_step(a: char, b: char) -> Option<i32> {
+    let same_kind = (a.is_ascii_lowercase() && b.is_ascii_lowercase()) || (a.is_ascii_digit() && b.is_ascii_digit());
+    let delta = b as i32 - a as i32;
+    (same_kind && matches!(delta, 1 | -1)).then_some(delta)
+}
+
+/// What l33t spelled characters are standing in for.
+fn unleet(c: char) -> char {
+    match c {
+        '0' => 'o',
+        '1' | '!' => 'i',
+        '3' => 'e',
+        '4' | '@' => 'a',
+        '5' | '$' => 's',
+        '7' => 't',
+        c => c,
+    }
+}
+
+/// How many different characters each one of the password could have been.
+fn pool_size(password: &str) -> f64 {
+    let mut pool = 0;
+    if password.chars().any(|c| c.is_ascii_lowercase()) {
+        pool += 26;
+    }
+    if password.chars().any(|c| c.is_ascii_uppercase()) {
+        pool += 26;
+    }
+    if password.chars().any(|c| c.is_ascii_digit()) {
+        pool += 10;
+    }
+    if password.chars().any(|c| c.is_ascii_punctuation() || c == ' ') {
+        pool += 33;
+    }
+    if !password.is_ascii() {
+        pool += 100;
+    }
+    pool.max(1) as f64
+}
+
+/// Is the password in the breached password list at `path`? The list has one
+/// SHA-1 hash per line, in hex and sorted, optionally followed by `:` and a count
+/// (the format of [Have I Been Pwned's](https://haveibeenpwned.com/Passwords) downloads).
+///
+/// It's binary searched on disk, so this blocks for a few reads and shouldn't be
+/// called from an async thread.
+pub fn is_breached(path: &Path, password: &str) -> io::Result<bool> {
+    let hash = hex::encode_upper(Sha1::digest(password.as_bytes()));
+    let mut file = BufReader::new(File::open(path)?);
+    let mut line = Vec::new();
+
+    // Looking for a line that starts somewhere in `low..high`.
+    let (mut low, mut high) = (0, file.get_ref().metadata()?.len());
+    while low < high {
+        let mid = low + (high - low) / 2;
+        let Some(start) = line_from(&mut file, mid, &mut line)? else {
+            high = mid;
+            continue;
+        };
+        if start >= high {
+            high = mid;
+            continue;
+        }
+        match compare(&line, &hash) {
+            Ordering::Equal => return Ok(true),
+            Ordering::Less => low = start + 1,
+            Ordering::Greater => high = mid,
+        }
+    }
+    Ok(false)
+}
+
+/// Read the first whole line that starts at or after `offset` into `line`,
+/// returning where it starts. `None` past the last line.
+fn line_from(file: &mut BufReader<File>, offset: u64, line: &mut Vec<u8>) -> io::Result<Option<u64>> {
+    let mut start = offset;
+    if offset > 0 {
+        // the byte before tells if `offset` is already at the start of a line
+        file.seek(SeekFrom::Start(offset - 1))?;
+        line.clear();
+        start = offset - 1 + file.read_until(b'\n', line)? as u64;
+    } else {
+        file.seek(SeekFrom::Start(0))?;
+    }
+    line.clear();
+    match file.read_until(b'\n', line)? {
+        0 => Ok(None),
+        _ => Ok(Some(start)),
+    }
+}
+
+/// The line's hash against the one searched for, ignoring case and the count.
+fn compare(line: &[u8], hash: &str) -> Ordering {
+    let end = line.iter().position(|b| *b == b':' || b.is_ascii_whitespace()).unwrap_or(line.len());
+    line[..end]
+        .iter()
+        .map(u8::to_ascii_uppercase)
+        .cmp(hash.bytes())
+}
+
+/// What [`calibrate`] came up with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Calibration {
+    pub hashing: HashingConfig,
+    /// How long a hash took with them on this machine.
+    pub took: Duration,
+}
+
+/// Benchmark this machine to find how many iterations a hash with `memory_cost`
+/// KiB and `parallelism` lanes needs to take at least `target`. Memory is what
+/// makes cracking expensive, so give it as much as you can spare for each login
+/// happening at once, and let the iterations make up the rest.
+///
+/// This hashes a few times, so run it in release mode on the machine that will be
+/// serving logins, ex: with `cargo run --release --example calibrate -- 500`.
+/// ```no_run
+/// use std::time::Duration;
+/// use rust_authentication::password::calibrate;
+///
+/// let calibration = calibrate(Duration::from_millis(500), 64 * 1024, 1).unwrap();
+/// println!("{:?} takes {:?}", calibration.hashing, calibration.took);
+/// ```
+pub fn calibrate(target: Duration, memory_cost: u32, parallelism: u32) -> Result<Calibration, argon2::password_hash::Error> {
+    let mut hashing = HashingConfig { memory_cost, iterations: 1, parallelism };
+    let mut took = time_hash(&hashing)?;
+    while took < target {
+        // each pass over the memory takes about as long as the others
+        let per_iteration = took.as_nanos() / hashing.iterations as u128;
+        let needed = target.as_nanos().div_ceil(per_iteration.max(1));
+        hashing.iterations = u32::try_from(needed).unwrap_or(u32::MAX).max(hashing.iterations + 1);
+        took = time_hash(&hashing)?;
+    }
+    Ok(Calibration { hashing, took })
+}
+
+fn time_hash(hashing: &HashingConfig) -> Result<Duration, argon2::password_hash::Error> {
+    let hasher = hashing.hasher()?;
+    let salt = SaltString::generate(OsRng);
+    let start = Instant::now();
+    hasher.hash_password(b"calibrating", &salt)?;
+    Ok(start.elapsed())
+}
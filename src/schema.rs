@@ -2,8 +2,43 @@
 
 diesel::table! {
     account (id) {
-        id -> Int4,
-        email -> Varchar,
-        password_hash -> Bytea,
+        id -> Integer,
+        email -> Text,
+        password_hash -> Binary,
+        email_verified -> Bool,
+        deleted_at -> Nullable<BigInt>,
+        status -> Text,
+        expires_at -> Nullable<BigInt>,
+        login_key -> Nullable<Text>,
     }
 }
+
+diesel::table! {
+    account_token (id) {
+        id -> Integer,
+        account_id -> Integer,
+        purpose -> Text,
+        token_hash -> Binary,
+        expires_at -> BigInt,
+        data -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    login_history (id) {
+        id -> Integer,
+        account_id -> Integer,
+        at -> BigInt,
+        ip -> Nullable<Text>,
+        user_agent -> Nullable<Text>,
+    }
+}
+
+diesel::joinable!(account_token -> account (account_id));
+diesel::joinable!(login_history -> account (account_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    account,
+    account_token,
+    login_history,
+);
@@ -0,0 +1,417 @@
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Arc;
+
+use rocket::tokio::sync::RwLock;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::authentication::LoginError;
+use crate::db::AccountStoreError;
+use crate::identifier::normalize;
+
+/// What Rocket manages so the routes and guards can find the accounts.
+/// [`crate::AuthFairing`] uses the configured database, unless you are
+/// already managing one of these yourself:
+/// ```no_run
+/// # use rocket::launch;
+/// use std::sync::Arc;
+/// use rust_authentication::{AuthFairing, store::{ManagedStore, MemoryStore}};
+///
+/// #[launch]
+/// fn rocket() -> _ {
+///     rocket::build()
+///         .manage::<ManagedStore>(Arc::new(MemoryStore::new()))
+///         .attach(AuthFairing::new())
+/// }
+/// ```
+pub type ManagedStore = Arc<dyn UserStore>;
+
+/// A user, as far as logging in is concerned.
+#[derive(Debug, Clone)]
+pub struct UserRecord {
+    pub id: i32,
+    /// What they log in with. (Their email)
+    pub login: String,
+    /// Usually an Argon2 PHC string, but imported accounts can have hashes in
+    /// other formats until they next log in, see [`crate::password::Hasher`].
+    pub password_hash: String,
+    /// Have they followed the link that was emailed to them?
+    pub verified: bool,
+    /// When they deleted their account, if they did. It can't be logged
+    /// into anymore, and gets purged once the grace period is over.
+    pub deleted_at: Option<i64>,
+    pub status: AccountStatus,
+    /// When the account stops being able to log in, if ever.
+    pub expires_at: Option<i64>,
+}
+
+impl UserRecord {
+    /// Can they log in (or stay logged in) as of `now`?
+    pub fn check_standing(&self, now: i64) -> Result<(), LoginError> {
+        // As far as logging in goes, deleted accounts are already gone.
+        if self.deleted_at.is_some() {
+            return Err(LoginError::NoAccount);
+        }
+        match self.status {
+            AccountStatus::Active           => {},
+            AccountStatus::Locked           => return Err(LoginError::Locked),
+            AccountStatus::Disabled         => return Err(LoginError::Disabled),
+            AccountStatus::PendingApproval  => return Err(LoginError::PendingApproval),
+        }
+        match self.expires_at {
+            Some(expires_at) if expires_at <= now => Err(LoginError::Expired),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Whether an account can log in, set by whoever runs the app.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountStatus {
+    #[default]
+    Active,
+    /// Shut temporarily, ex: after suspicious activity.
+    Locked,
+    /// Shut for good, by an admin.
+    Disabled,
+    /// Waiting on an admin to let them in.
+    PendingApproval,
+}
+
+impl AccountStatus {
+    /// How it is written in the database.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AccountStatus::Active           => "active",
+            AccountStatus::Locked           => "locked",
+            AccountStatus::Disabled         => "disabled",
+            AccountStatus::PendingApproval  => "pending_approval",
+        }
+    }
+}
+
+impl std::str::FromStr for AccountStatus {
+    type Err = AccountStoreError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "active"            => Ok(AccountStatus::Active),
+            "locked"            => Ok(AccountStatus::Locked),
+            "disabled"          => Ok(AccountStatus::Disabled),
+            "pending_approval"  => Ok(AccountStatus::PendingApproval),
+            other => Err(AccountStoreError::Corrupt(format!("unknown account status '{other}'"))),
+        }
+    }
+}
+
+/// Where accounts are kept. Implement this to log users in against your own
+/// users table, the SQLite and Postgres [`crate::AccountDb`] is the default.
+///
+/// Only the first four methods are needed to log in and sign up. The rest (tokens,
+/// verification, deletion, login history...) fail with [`AccountStoreError::Unsupported`]
+/// unless implemented, and the routes that need them answer with a 501.
+#[rocket::async_trait]
+pub trait UserStore: Send + Sync {
+    /// `None` if no one logs in with that. Logins should be matched on their
+    /// normalised form, see [`crate::identifier::normalize`].
+    async fn find_by_login(&self, login: &str) -> Result<Option<UserRecord>, AccountStoreError>;
+    /// `None` if there is no user with that id.
+    async fn find_by_id(&self, id: i32) -> Result<Option<UserRecord>, AccountStoreError>;
+    /// Add a new user, the password will already be hashed (and may have been
+    /// hashed elsewhere, if the account is being imported).
+    /// Should fail with [`AccountStoreError::Conflict`] if the login is taken,
+    /// once normalised.
+    async fn create(&self, login: &str, password_hash: &str) -> Result<UserRecord, AccountStoreError>;
+    /// Replace the user's password hash.
+    /// Should fail with [`AccountStoreError::NotFound`] if there is no user with that id.
+    async fn update_credentials(&self, id: i32, password_hash: &str) -> Result<(), AccountStoreError>;
+    // Everything below is optional. A store that doesn't do it gets
+    // `AccountStoreError::Unsupported`, and the routes that need it answer 501.
+
+    /// Change what they log in with.
+    /// Should fail with [`AccountStoreError::Conflict`] if the login is taken once normalised,
+    /// or [`AccountStoreError::NotFound`] if there is no user with that id.
+    async fn update_login(&self, _id: i32, _login: &str) -> Result<(), AccountStoreError> {
+        Err(AccountStoreError::Unsupported("update_login"))
+    }
+    /// Their email has been shown to be theirs.
+    /// Should fail with [`AccountStoreError::NotFound`] if there is no user with that id.
+    async fn mark_verified(&self, _id: i32) -> Result<(), AccountStoreError> {
+        Err(AccountStoreError::Unsupported("mark_verified"))
+    }
+    /// Change whether they can log in.
+    /// Should fail with [`AccountStoreError::NotFound`] if there is no user with that id.
+    async fn set_status(&self, _id: i32, _status: AccountStatus) -> Result<(), AccountStoreError> {
+        Err(AccountStoreError::Unsupported("set_status"))
+    }
+    /// Have the account expire at `expires_at`, or never with `None`.
+    /// Should fail with [`AccountStoreError::NotFound`] if there is no user with that id.
+    async fn set_expiry(&self, _id: i32, _expires_at: Option<i64>) -> Result<(), AccountStoreError> {
+        Err(AccountStoreError::Unsupported("set_expiry"))
+    }
+    /// Soft-delete the account, as of `at`.
+    /// Should fail with [`AccountStoreError::NotFound`] if there is no user with that id.
+    async fn mark_deleted(&self, _id: i32, _at: i64) -> Result<(), AccountStoreError> {
+        Err(AccountStoreError::Unsupported("mark_deleted"))
+    }
+    /// Remove every account that was deleted at or before `before` for good,
+    /// along with their tokens. Returns the accounts that were removed.
+    async fn purge_deleted(&self, _before: i64) -> Result<Vec<UserRecord>, AccountStoreError> {
+        Err(AccountStoreError::Unsupported("purge_deleted"))
+    }
+    /// Remember that they logged in.
+    async fn record_login(&self, _id: i32, _login: LoginRecord) -> Result<(), AccountStoreError> {
+        Err(AccountStoreError::Unsupported("record_login"))
+    }
+    /// Every login that was recorded for them, oldest first.
+    async fn login_history(&self, _id: i32) -> Result<Vec<LoginRecord>, AccountStoreError> {
+        Err(AccountStoreError::Unsupported("login_history"))
+    }
+    /// Keep the hash of a token that was sent to the user, in place of any other
+    /// token they had for the same purpose (if [`TokenPurpose::replaces_earlier`]).
+    /// `data` is handed back when it gets used.
+    async fn create_token(&self, _id: i32, _purpose: TokenPurpose, _token_hash: Vec<u8>, _expires_at: i64, _data: Option<String>) -> Result<(), AccountStoreError> {
+        Err(AccountStoreError::Unsupported("create_token"))
+    }
+    /// Remove the token with this hash and return who it belongs to. `None` if there
+    /// is no such token for that purpose, or it expired before `now`.
+    async fn take_token(&self, _purpose: TokenPurpose, _token_hash: &[u8], _now: i64) -> Result<Option<RedeemedToken>, AccountStoreError> {
+        Err(AccountStoreError::Unsupported("take_token"))
+    }
+    /// Who the token with this hash belongs to, without using it up. `None` in the
+    /// same cases as [`Self::take_token`].
+    async fn peek_token(&self, _purpose: TokenPurpose, _token_hash: &[u8], _now: i64) -> Result<Option<RedeemedToken>, AccountStoreError> {
+        Err(AccountStoreError::Unsupported("peek_token"))
+    }
+    /// Does the account have a token for `purpose` that hasn't expired as of `now`?
+    async fn has_token(&self, _id: i32, _purpose: TokenPurpose, _now: i64) -> Result<bool, AccountStoreError> {
+        Err(AccountStoreError::Unsupported("has_token"))
+    }
+}
+
+/// One time an account logged in.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LoginRecord {
+    /// In seconds since the unix epoch.
+    pub at: i64,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// Who a token belongs to, ex: one that was just used up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedeemedToken {
+    pub account_id: i32,
+    /// What was stored alongside it.
+    pub data: Option<String>,
+}
+
+/// What a token that was emailed to a user lets them do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenPurpose {
+    PasswordReset,
+    VerifyEmail,
+    /// Holds the address they are changing to.
+    ChangeEmail,
+    /// Holds the address they changed from.
+    RevertEmail,
+}
+
+impl TokenPurpose {
+    /// How it is written in the database.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TokenPurpose::PasswordReset => "password_reset",
+            TokenPurpose::VerifyEmail   => "verify_email",
+            TokenPurpose::ChangeEmail   => "change_email",
+            TokenPurpose::RevertEmail   => "revert_email",
+        }
+    }
+
+    /// Does a new token do away with the ones the account had for this before?
+    /// A link to revert an email change goes to the address it was changed from,
+    /// so changing it again mustn't take that link away from them.
+    pub fn replaces_earlier(self) -> bool {
+        !matches!(self, TokenPurpose::RevertEmail)
+    }
+}
+
+/// A token held by [`MemoryStore`].
+struct StoredToken {
+    account_id: i32,
+    purpose: TokenPurpose,
+    token_hash: Vec<u8>,
+    expires_at: i64,
+    data: Option<String>,
+}
+
+/// Keeps accounts in memory, they are gone once it's dropped.
+/// Mostly useful for tests.
+#[derive(Default)]
+pub struct MemoryStore {
+    users: RwLock<Vec<UserRecord>>,
+    tokens: RwLock<Vec<StoredToken>>,
+    logins: RwLock<Vec<(i32, LoginRecord)>>,
+    last_id: AtomicI32,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[rocket::async_trait]
+impl UserStore for MemoryStore {
+    async fn find_by_login(&self, login: &str) -> Result<Option<UserRecord>, AccountStoreError> {
+        let login = normalize(login);
+        Ok(self.users.read().await.iter().find(|u| normalize(&u.login) == login).cloned())
+    }
+
+    async fn find_by_id(&self, id: i32) -> Result<Option<UserRecord>, AccountStoreError> {
+        Ok(self.users.read().await.iter().find(|u| u.id == id).cloned())
+    }
+
+    async fn create(&self, login: &str, password_hash: &str) -> Result<UserRecord, AccountStoreError> {
+        let mut users = self.users.write().await;
+        let key = normalize(login);
+        if users.iter().any(|u| normalize(&u.login) == key) {
+            return Err(AccountStoreError::Conflict);
+        }
+
+        let user = UserRecord {
+            // ids start at 1 and aren't reused once purged, just like the databases
+            id: self.last_id.fetch_add(1, Ordering::SeqCst) + 1,
+            login: login.trim().to_owned(),
+            password_hash: password_hash.to_owned(),
+            verified: false,
+            deleted_at: None,
+            status: AccountStatus::Active,
+            expires_at: None,
+        };
+        users.push(user.clone());
+        Ok(user)
+    }
+
+    async fn update_credentials(&self, id: i32, password_hash: &str) -> Result<(), AccountStoreError> {
+        match self.users.write().await.iter_mut().find(|u| u.id == id) {
+            Some(user) => {
+                user.password_hash = password_hash.to_owned();
+                Ok(())
+            },
+            None => Err(AccountStoreError::NotFound),
+        }
+    }
+
+    async fn update_login(&self, id: i32, login: &str) -> Result<(), AccountStoreError> {
+        let mut users = self.users.write().await;
+        let key = normalize(login);
+        if users.iter().any(|u| normalize(&u.login) == key && u.id != id) {
+            return Err(AccountStoreError::Conflict);
+        }
+
+        match users.iter_mut().find(|u| u.id == id) {
+            Some(user) => {
+                user.login = login.trim().to_owned();
+                Ok(())
+            },
+            None => Err(AccountStoreError::NotFound),
+        }
+    }
+
+    async fn mark_verified(&self, id: i32) -> Result<(), AccountStoreError> {
+        match self.users.write().await.iter_mut().find(|u| u.id == id) {
+            Some(user) => {
+                user.verified = true;
+                Ok(())
+            },
+            None => Err(AccountStoreError::NotFound),
+        }
+    }
+
+    async fn set_status(&self, id: i32, status: AccountStatus) -> Result<(), AccountStoreError> {
+        match self.users.write().await.iter_mut().find(|u| u.id == id) {
+            Some(user) => {
+                user.status = status;
+                Ok(())
+            },
+            None => Err(AccountStoreError::NotFound),
+        }
+    }
+
+    async fn set_expiry(&self, id: i32, expires_at: Option<i64>) -> Result<(), AccountStoreError> {
+        match self.users.write().await.iter_mut().find(|u| u.id == id) {
+            Some(user) => {
+                user.expires_at = expires_at;
+                Ok(())
+            },
+            None => Err(AccountStoreError::NotFound),
+        }
+    }
+
+    async fn mark_deleted(&self, id: i32, at: i64) -> Result<(), AccountStoreError> {
+        match self.users.write().await.iter_mut().find(|u| u.id == id) {
+            Some(user) => {
+                user.deleted_at = Some(at);
+                Ok(())
+            },
+            None => Err(AccountStoreError::NotFound),
+        }
+    }
+
+    async fn purge_deleted(&self, before: i64) -> Result<Vec<UserRecord>, AccountStoreError> {
+        let mut users = self.users.write().await;
+        let (purged, kept) = users
+            .drain(..)
+            .partition::<Vec<_>, _>(|u| u.deleted_at.is_some_and(|at| at <= before));
+        *users = kept;
+
+        self.tokens.write().await.retain(|t| purged.iter().all(|u| u.id != t.account_id));
+        self.logins.write().await.retain(|(id, _)| purged.iter().all(|u| u.id != *id));
+        Ok(purged)
+    }
+
+    async fn record_login(&self, id: i32, login: LoginRecord) -> Result<(), AccountStoreError> {
+        self.logins.write().await.push((id, login));
+        Ok(())
+    }
+
+    async fn login_history(&self, id: i32) -> Result<Vec<LoginRecord>, AccountStoreError> {
+        Ok(self.logins.read().await.iter().filter(|(user, _)| *user == id).map(|(_, login)| login.clone()).collect())
+    }
+
+    async fn create_token(&self, id: i32, purpose: TokenPurpose, token_hash: Vec<u8>, expires_at: i64, data: Option<String>) -> Result<(), AccountStoreError> {
+        if self.find_by_id(id).await?.is_none() {
+            return Err(AccountStoreError::NotFound);
+        }
+
+        let mut tokens = self.tokens.write().await;
+        if purpose.replaces_earlier() {
+            tokens.retain(|t| t.account_id != id || t.purpose != purpose);
+        }
+        tokens.push(StoredToken { account_id: id, purpose, token_hash, expires_at, data });
+        Ok(())
+    }
+
+    async fn take_token(&self, purpose: TokenPurpose, token_hash: &[u8], now: i64) -> Result<Option<RedeemedToken>, AccountStoreError> {
+        let mut tokens = self.tokens.write().await;
+        let found = tokens.iter().position(|t| t.purpose == purpose && t.token_hash == token_hash);
+        Ok(found
+            .map(|i| tokens.remove(i))
+            .filter(|t| t.expires_at > now)
+            .map(|t| RedeemedToken { account_id: t.account_id, data: t.data }))
+    }
+
+    async fn peek_token(&self, purpose: TokenPurpose, token_hash: &[u8], now: i64) -> Result<Option<RedeemedToken>, AccountStoreError> {
+        Ok(self.tokens.read().await
+            .iter()
+            .find(|t| t.purpose == purpose && t.token_hash == token_hash && t.expires_at > now)
+            .map(|t| RedeemedToken { account_id: t.account_id, data: t.data.clone() }))
+    }
+
+    async fn has_token(&self, id: i32, purpose: TokenPurpose, now: i64) -> Result<bool, AccountStoreError> {
+        Ok(self.tokens.read().await
+            .iter()
+            .any(|t| t.account_id == id && t.purpose == purpose && t.expires_at > now))
+    }
+}
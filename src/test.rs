@@ -1,10 +1,13 @@
 #![allow(clippy::todo)]
 #![allow(clippy::unimplemented)]
+#![allow(clippy::unwrap_used)]
+#![allow(clippy::panic)]
 
 mod tests {
 
     #[allow(unused_imports)]
     use rocket::{
+        get,
         http::{ContentType, Status, Header},
         local::blocking::Client,
         routes, uri, Build, Rocket,
@@ -15,14 +18,57 @@ mod tests {
     #[allow(unused_imports)]
     use crate::{
         auth::{self, authentication},
-        get_state, pages,
+        config::AuthRoute,
+        get_state, pages, store::UserRecord,
+        AuthFairing, AuthUser, LoadError, Session, UserLoader,
     };
 
+    #[get("/whoami")]
+    fn whoami(user: AuthUser<UserRecord>) -> String {
+        format!("{} {}", user.session.account_id, user.user.login)
+    }
+
+    /// The email kept with the session.
+    #[allow(dead_code)]
+    #[get("/session_email")]
+    fn session_email(session: Session) -> String {
+        session.email
+    }
+
+    static LOADS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+    /// Counts how many times it gets loaded.
+    #[derive(Clone)]
+    struct CountedUser;
+
+    #[rocket::async_trait]
+    impl UserLoader for CountedUser {
+        async fn load(_: &Session, _: &rocket::Request<'_>) -> Result<Option<Self>, LoadError> {
+            LOADS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(Some(CountedUser))
+        }
+    }
+
+    #[get("/double_user")]
+    fn double_user(_first: AuthUser<CountedUser>, _second: AuthUser<CountedUser>) -> Status {
+        Status::Accepted
+    }
+
+    /// Takes two sessions, both should be the same one.
+    #[get("/double_guard")]
+    fn double_guard(first: Session, second: Session) -> Status {
+        if first.uuid == second.uuid {
+            Status::Accepted
+        } else {
+            Status::Conflict
+        }
+    }
+
 
     #[allow(dead_code)]
     fn rm_database() {
         
-        #[cfg(not(feature = "postgres"))]
+        #[cfg(feature = "sqlite")]
         {
             use crate::db::SQLITE_DATABASE_LOCATION;
             if let Err(e) = std::fs::remove_file(SQLITE_DATABASE_LOCATION) {
@@ -38,7 +84,7 @@ mod tests {
                 trace!("Successfully removed database file");
             }
         }
-        #[cfg(feature = "postgres")]
+        #[cfg(not(feature = "sqlite"))]
         {
             unimplemented!("Clearing of postgres database is not yet done");
         }
@@ -47,16 +93,13 @@ mod tests {
     #[allow(dead_code)]
     fn get_rocket() -> Rocket<Build> {
         rocket::build()
-            .mount(
-                "/",
-                routes![pages::login, pages::logout, pages::create_account],
-            )
-            .manage(get_state())
+            .mount("/", routes![double_guard, whoami, double_user])
+            .attach(AuthFairing::new())
     }
 
     #[allow(dead_code)]
     /// Making sure a user with
-    /// `loginTester` `testing` exists.
+    /// `tester@example.com` `testing123` exists.
     fn ensure_testing_account(client: &Client) {
         let res = client
             .post(uri!(pages::create_account))
@@ -64,8 +107,8 @@ mod tests {
             .body(
                 r#"
                 {
-                    "name": "loginTester",
-                    "password": "testing"
+                    "name": "tester@example.com",
+                    "password": "testing123"
                 }"#,
             )
             .dispatch();
@@ -102,7 +145,7 @@ mod tests {
             .body(
                 r#"
         {
-            "name": "HarryPotter",
+            "name": "harry@hogwarts.example",
             "password": "ISolemnlySwearI'mUpToNoGood."
         } 
         "#,
@@ -119,8 +162,8 @@ mod tests {
             .body(
                 r#"
         {
-            "name": "HarryPotter",
-            "password": "other"
+            "name": "harry@hogwarts.example",
+            "password": "otherPassword"
         } 
         "#,
             )
@@ -144,9 +187,9 @@ mod tests {
             .get(uri!(pages::login))
             .header(Header::new(
                 authentication::USERNAME_HEADER_ID,
-                "loginTester",
+                "tester@example.com",
             ))
-            .header(Header::new(authentication::PASSWORD_HEADER_ID, "testing"))
+            .header(Header::new(authentication::PASSWORD_HEADER_ID, "testing123"))
             .dispatch();
 
         assert_eq!(res.status(), Status::Accepted);
@@ -167,4 +210,1523 @@ mod tests {
             panic!("Cookie was not set!")
         }
     }
+
+    #[test]
+    fn session_resolved_once_per_request() {
+        debug!("Using two session guards on one request.");
+        let rocket = get_rocket();
+        let client = Client::tracked(rocket).unwrap();
+
+        ensure_testing_account(&client);
+
+        // Logging in via headers should only create one session for both guards.
+        let res = client
+            .get(uri!(double_guard))
+            .header(Header::new(
+                authentication::USERNAME_HEADER_ID,
+                "tester@example.com",
+            ))
+            .header(Header::new(authentication::PASSWORD_HEADER_ID, "testing123"))
+            .dispatch();
+
+        assert_eq!(res.status(), Status::Accepted);
+
+        // and again via the cookie
+        let res = client.get(uri!(double_guard)).dispatch();
+        assert_eq!(res.status(), Status::Accepted);
+    }
+
+    #[test]
+    fn fairing_mounts_subset_under_base() {
+        debug!("Mounting only the login route under /auth");
+        let rocket = rocket::build()
+            .attach(AuthFairing::new().base_path("/auth").routes([AuthRoute::Login]));
+        let client = Client::tracked(rocket).unwrap();
+
+        let res = client.get("/auth/login").dispatch();
+        assert_eq!(res.status(), Status::Unauthorized);
+
+        let res = client.get("/login").dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+
+        let res = client.post("/auth/create_account").dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn fairing_reads_rocket_config() {
+        debug!("Overriding the fairing's base path from Rocket's config");
+        let figment = rocket::Config::figment().merge(("auth.base_path", "/from_config"));
+        let rocket = rocket::custom(figment).attach(AuthFairing::new().base_path("/auth"));
+        let client = Client::tracked(rocket).unwrap();
+
+        let res = client.get("/from_config/login").dispatch();
+        assert_eq!(res.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn fairing_rejects_bad_config() {
+        debug!("Igniting with an invalid base path");
+        fn fails_ignite(rocket: Rocket<Build>) -> bool {
+            // rocket's errors panic if dropped without being looked at
+            match Client::tracked(rocket) {
+                Ok(_) => false,
+                Err(e) => matches!(e.kind(), rocket::error::ErrorKind::FailedFairings(_)),
+            }
+        }
+
+        assert!(fails_ignite(rocket::build().attach(AuthFairing::new().base_path("no slash"))));
+        assert!(fails_ignite(rocket::build()
+            .attach(AuthFairing::new().routes([AuthRoute::Login, AuthRoute::Login]))));
+
+        let figment = rocket::Config::figment().merge(("auth.pool.max_connections", 0));
+        assert!(fails_ignite(rocket::custom(figment).attach(AuthFairing::new())));
+        let figment = rocket::Config::figment().merge(("auth.hashing.parallelism", 0));
+        assert!(fails_ignite(rocket::custom(figment).attach(AuthFairing::new())));
+        let figment = rocket::Config::figment().merge(("auth.password.min_length", 200));
+        assert!(fails_ignite(rocket::custom(figment).attach(AuthFairing::new())));
+        let figment = rocket::Config::figment().merge(("auth.password.breached_list", "no/such/list.txt"));
+        assert!(fails_ignite(rocket::custom(figment).attach(AuthFairing::new())));
+
+        #[cfg(not(feature = "redis"))]
+        {
+            let figment = rocket::Config::figment().merge(("auth.sessions.backend", "redis"));
+            assert!(fails_ignite(rocket::custom(figment).attach(AuthFairing::new())));
+        }
+
+    }
+
+    #[test]
+    fn accounts_backend_from_config() {
+        debug!("Picking a different sqlite database at runtime");
+        let path = "test_runtime_backend.sqlite";
+        let _ = std::fs::remove_file(path);
+
+        let figment = rocket::Config::figment()
+            .merge(("auth.accounts", rocket::figment::util::map!["backend" => "sqlite", "path" => path]));
+        let client = Client::tracked(rocket::custom(figment).attach(AuthFairing::new())).unwrap();
+
+        ensure_testing_account(&client);
+        assert!(std::path::Path::new(path).exists());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn baseline_sqlite_upgraded() {
+        use diesel::{Connection, RunQueryDsl, SqliteConnection};
+
+        debug!("Starting on a sqlite database made before there were migrations");
+        let path = "test_baseline.sqlite";
+        let _ = std::fs::remove_file(path);
+        let hash = crate::auth::keyring::Keyring::<dyn crate::auth::keyring::KeyStorage>::hash_password("testing123", &Default::default()).unwrap();
+        {
+            // what the old `src/new.sql` made
+            let mut conn = SqliteConnection::establish(path).unwrap();
+            diesel::sql_query("CREATE TABLE IF NOT EXISTS account (
+                id              INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                username        VARCHAR UNIQUE NOT NULL,
+                password_hash   BYTEA NOT NULL
+            );").execute(&mut conn).unwrap();
+            diesel::sql_query(format!("INSERT INTO account (username, password_hash) VALUES ('Old@example.com', CAST('{}' AS BLOB))", hash.as_str()))
+                .execute(&mut conn)
+                .unwrap();
+        }
+
+        let figment = rocket::Config::figment()
+            .merge(("auth.accounts", rocket::figment::util::map!["backend" => "sqlite", "path" => path]));
+        let client = Client::tracked(rocket::custom(figment).attach(AuthFairing::new())).unwrap();
+        let res = client
+            .get(uri!(pages::login))
+            .header(Header::new(authentication::USERNAME_HEADER_ID, "old@example.com"))
+            .header(Header::new(authentication::PASSWORD_HEADER_ID, "testing123"))
+            .dispatch();
+        assert_eq!(res.status(), Status::Accepted);
+
+        // and new accounts carry on after the old ones
+        let res = client
+            .post(uri!(pages::create_account))
+            .header(ContentType::JSON)
+            .body(r#"{ "name": "new@example.com", "password": "testing123" }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Accepted);
+        let res = client
+            .get(uri!(pages::login))
+            .header(Header::new(authentication::USERNAME_HEADER_ID, "new@example.com"))
+            .header(Header::new(authentication::PASSWORD_HEADER_ID, "testing123"))
+            .dispatch();
+        assert_eq!(res.status(), Status::Accepted);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn unreachable_backend_at_startup() {
+        debug!("Starting with an account database that can't be opened");
+        let accounts = rocket::figment::util::map![
+            "backend" => "sqlite",
+            "path" => "/this/path/does/not/exist.sqlite"
+        ];
+        let figment = rocket::Config::figment()
+            .merge(("auth.accounts", accounts))
+            .merge(("auth.pool.connect_timeout", 1));
+
+        // Refuses to launch by default
+        match Client::tracked(rocket::custom(figment.clone()).attach(AuthFairing::new())) {
+            Ok(_) => panic!("Launched without a database"),
+            Err(e) => assert!(matches!(e.kind(), rocket::error::ErrorKind::FailedFairings(_))),
+        }
+
+        // Or launches anyways, failing the requests instead.
+        let rocket = rocket::custom(figment).attach(AuthFairing::new().require_backends(false));
+        let client = Client::tracked(rocket).unwrap();
+        let res = client
+            .post(uri!(pages::create_account))
+            .header(ContentType::JSON)
+            .body(r#"{ "name": "nobody@example.com", "password": "nothing123" }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::ServiceUnavailable);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[rocket::async_test]
+    async fn migrations_run_once() {
+        use crate::{config::{AccountBackend, PoolConfig}, AccountDb};
+
+        debug!("Running the embedded migrations against a fresh database");
+        let path = "test_migrations.sqlite";
+        let _ = std::fs::remove_file(path);
+
+        let backend = AccountBackend::Sqlite { path: path.to_string() };
+        let accounts = AccountDb::new(&backend, &PoolConfig::default()).unwrap();
+
+        let applied = accounts.run_migrations().await.unwrap();
+        assert!(applied.contains(&"20230423001224".to_string()));
+        assert_eq!(accounts.applied_migrations().await.unwrap().len(), applied.len());
+
+        // Nothing left to do the second time around
+        assert!(accounts.run_migrations().await.unwrap().is_empty());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[rocket::async_test]
+    async fn migrations_upgrade_baseline() {
+        use diesel::{Connection, RunQueryDsl, SqliteConnection};
+        use crate::{config::{AccountBackend, PoolConfig}, store::{AccountStatus, UserStore}, AccountDb};
+
+        debug!("Running every migration against a database in the shape from before the migrations");
+        let fresh_path = "test_migrations_fresh.sqlite";
+        let path = "test_migrations_baseline.sqlite";
+        let _ = std::fs::remove_file(fresh_path);
+        let _ = std::fs::remove_file(path);
+        {
+            let mut conn = SqliteConnection::establish(path).unwrap();
+            diesel::sql_query("CREATE TABLE IF NOT EXISTS account (
+                id              INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                username        VARCHAR UNIQUE NOT NULL,
+                password_hash   BYTEA NOT NULL
+            );").execute(&mut conn).unwrap();
+            for (id, username) in [(3, "first@example.com"), (7, "second@example.com")] {
+                diesel::sql_query(format!("INSERT INTO account (id, username, password_hash) VALUES ({id}, '{username}', CAST('hash' AS BLOB))"))
+                    .execute(&mut conn)
+                    .unwrap();
+            }
+        }
+
+        let fresh = AccountDb::new(&AccountBackend::Sqlite { path: fresh_path.to_string() }, &PoolConfig::default()).unwrap();
+        let accounts = AccountDb::new(&AccountBackend::Sqlite { path: path.to_string() }, &PoolConfig::default()).unwrap();
+        let applied = accounts.run_migrations().await.unwrap();
+        assert_eq!(applied, fresh.run_migrations().await.unwrap());
+        assert!(accounts.run_migrations().await.unwrap().is_empty());
+        assert!(accounts.normalize_logins().await.unwrap().is_empty());
+
+        // everything that was there is still there, under the same ids
+        let first = accounts.find_by_login("first@example.com").await.unwrap().unwrap();
+        assert_eq!((first.id, first.password_hash.as_str()), (3, "hash"));
+        assert!(first.verified);
+        assert_eq!(first.status, AccountStatus::Active);
+        assert_eq!(accounts.find_by_id(7).await.unwrap().unwrap().login, "second@example.com");
+        assert_eq!(accounts.create("third@example.com", "hash").await.unwrap().id, 8);
+
+        let columns = |path: &str| {
+            #[derive(diesel::QueryableByName, PartialEq, Debug)]
+            struct Column {
+                #[diesel(sql_type = diesel::sql_types::Text)]
+                name: String,
+                #[diesel(sql_type = diesel::sql_types::Text)]
+                r#type: String,
+            }
+            let mut conn = SqliteConnection::establish(path).unwrap();
+            diesel::sql_query("SELECT name, type FROM pragma_table_info('account')").load::<Column>(&mut conn).unwrap()
+        };
+        assert_eq!(columns(path), columns(fresh_path));
+        let _ = std::fs::remove_file(fresh_path);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn login_wrong_password() {
+        debug!("Logging in with the wrong password, and to an account that doesn't exist.");
+        let client = Client::tracked(get_rocket()).unwrap();
+        ensure_testing_account(&client);
+
+        let res = client
+            .get(uri!(pages::login))
+            .header(Header::new(authentication::USERNAME_HEADER_ID, "tester@example.com"))
+            .header(Header::new(authentication::PASSWORD_HEADER_ID, "not it"))
+            .dispatch();
+        assert_eq!(res.status(), Status::Unauthorized);
+
+        let res = client
+            .get(uri!(pages::login))
+            .header(Header::new(authentication::USERNAME_HEADER_ID, "nobodyAtAll"))
+            .header(Header::new(authentication::PASSWORD_HEADER_ID, "testing123"))
+            .dispatch();
+        assert_eq!(res.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn custom_user_store() {
+        use crate::store::{ManagedStore, MemoryStore};
+
+        debug!("Logging in against an in-memory user store");
+        let rocket = rocket::build()
+            .manage::<ManagedStore>(std::sync::Arc::new(MemoryStore::new()))
+            .attach(AuthFairing::new());
+        let client = Client::tracked(rocket).unwrap();
+
+        ensure_testing_account(&client);
+
+        let res = client
+            .get(uri!(pages::login))
+            .header(Header::new(authentication::USERNAME_HEADER_ID, "tester@example.com"))
+            .header(Header::new(authentication::PASSWORD_HEADER_ID, "testing123"))
+            .dispatch();
+        assert_eq!(res.status(), Status::Accepted);
+
+        // The configured database was never touched.
+        #[cfg(any(feature = "sqlite", feature = "postgres"))]
+        assert!(client.rocket().state::<crate::AccountDb>().is_none());
+    }
+
+    #[test]
+    fn minimal_user_store() {
+        use rocket::serde::json::Value;
+        use crate::{store::{ManagedStore, MemoryStore, UserStore}, AccountStoreError};
+
+        /// Only what's needed to log in, like a team's existing users table would have.
+        struct UsersTable(MemoryStore);
+
+        #[rocket::async_trait]
+        impl UserStore for UsersTable {
+            async fn find_by_login(&self, login: &str) -> Result<Option<UserRecord>, AccountStoreError> {
+                self.0.find_by_login(login).await
+            }
+            async fn find_by_id(&self, id: i32) -> Result<Option<UserRecord>, AccountStoreError> {
+                self.0.find_by_id(id).await
+            }
+            async fn create(&self, login: &str, password_hash: &str) -> Result<UserRecord, AccountStoreError> {
+                self.0.create(login, password_hash).await
+            }
+            async fn update_credentials(&self, id: i32, password_hash: &str) -> Result<(), AccountStoreError> {
+                self.0.update_credentials(id, password_hash).await
+            }
+        }
+
+        debug!("Using a store that only has the required methods");
+        let rocket = rocket::build()
+            .manage::<ManagedStore>(std::sync::Arc::new(UsersTable(MemoryStore::new())))
+            .attach(AuthFairing::new());
+        let client = Client::tracked(rocket).unwrap();
+
+        // signing up and logging in work, without the verification email
+        ensure_testing_account(&client);
+        let session = login_cookie(&client, "tester@example.com", "testing123").unwrap();
+        let res = client
+            .post(uri!(pages::change_password))
+            .private_cookie(session.clone())
+            .header(ContentType::JSON)
+            .body(r#"{ "new_password": "changed123" }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Accepted);
+
+        // with no login history to hand over
+        let res = client.get(uri!(pages::export_account)).private_cookie(session.clone()).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let export: Value = rocket::serde::json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(export["login_history"].as_array().unwrap().len(), 0);
+
+        // asking for a reset link answers the same as for an account that doesn't exist
+        for email in ["tester@example.com", "nobody@example.com"] {
+            let res = client
+                .post(uri!(pages::request_password_reset))
+                .header(ContentType::JSON)
+                .body(format!(r#"{{ "email": "{email}" }}"#))
+                .dispatch();
+            assert_eq!(res.status(), Status::Accepted);
+            assert_eq!(res.into_string().unwrap(), "If that account exists, a reset link was sent to it.");
+        }
+
+        // what needs more than that isn't implemented
+        let res = client.get(uri!(pages::verify_email("anything"))).dispatch();
+        assert_eq!(res.status(), Status::NotImplemented);
+        let res = client
+            .delete(uri!(pages::delete_account))
+            .private_cookie(session)
+            .header(ContentType::JSON)
+            .body(r#"{ "current_password": "changed123" }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::NotImplemented);
+        assert!(login_cookie(&client, "tester@example.com", "changed123").is_some());
+    }
+
+    #[test]
+    fn auth_user_loaded_once() {
+        debug!("Loading the user behind a session");
+        let client = Client::tracked(get_rocket()).unwrap();
+        ensure_testing_account(&client);
+
+        let res = client
+            .get(uri!(whoami))
+            .header(Header::new(authentication::USERNAME_HEADER_ID, "tester@example.com"))
+            .header(Header::new(authentication::PASSWORD_HEADER_ID, "testing123"))
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let body = res.into_string().unwrap();
+        let (id, login) = body.split_once(' ').unwrap();
+        assert!(id.parse::<i32>().unwrap() > 0);
+        assert_eq!(login, "tester@example.com");
+
+        // Two guards, one load.
+        let before = LOADS.load(std::sync::atomic::Ordering::SeqCst);
+        let res = client.get(uri!(double_user)).dispatch();
+        assert_eq!(res.status(), Status::Accepted);
+        assert_eq!(LOADS.load(std::sync::atomic::Ordering::SeqCst), before + 1);
+    }
+
+    #[allow(dead_code)]
+    /// Log in with the given credentials, returning the session cookie.
+    fn login_cookie(client: &Client, email: &str, password: &str) -> Option<rocket::http::Cookie<'static>> {
+        let res = client
+            .get(uri!(pages::login))
+            .header(Header::new(authentication::USERNAME_HEADER_ID, email.to_string()))
+            .header(Header::new(authentication::PASSWORD_HEADER_ID, password.to_string()))
+            .dispatch();
+        res.cookies().get_private(authentication::SESSION_COOKIE_ID)
+    }
+
+    #[allow(dead_code)]
+    /// Wait for the emails that get sent after responding, until there are `count` of them.
+    fn wait_for_mail(mailer: &crate::mail::MemoryMailer, count: usize) -> Vec<crate::mail::Email> {
+        for _ in 0..100 {
+            let sent = mailer.sent();
+            if sent.len() >= count {
+                return sent;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        mailer.sent()
+    }
+
+    #[test]
+    fn change_password_revokes_other_sessions() {
+        use crate::store::{ManagedStore, MemoryStore};
+
+        debug!("Changing the password from one session, while logged in on another");
+        let rocket = rocket::build()
+            .manage::<ManagedStore>(std::sync::Arc::new(MemoryStore::new()))
+            .attach(AuthFairing::new());
+        let client = Client::untracked(rocket).unwrap();
+        ensure_testing_account(&client);
+
+        let first = login_cookie(&client, "tester@example.com", "testing123").unwrap();
+        let second = login_cookie(&client, "tester@example.com", "testing123").unwrap();
+
+        // wrong current password
+        let res = client
+            .post(uri!(pages::change_password))
+            .private_cookie(first.clone())
+            .header(ContentType::JSON)
+            .body(r#"{ "current_password": "nope", "new_password": "changed123" }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Unauthorized);
+
+        let res = client
+            .post(uri!(pages::change_password))
+            .private_cookie(first.clone())
+            .header(ContentType::JSON)
+            .body(r#"{ "current_password": "testing123", "new_password": "changed123" }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Accepted);
+
+        // the session that changed it is still good, the other one isn't
+        let res = client.get(uri!(pages::login)).private_cookie(first).dispatch();
+        assert_eq!(res.status(), Status::Accepted);
+        let res = client.get(uri!(pages::login)).private_cookie(second).dispatch();
+        assert_eq!(res.status(), Status::Unauthorized);
+
+        assert!(login_cookie(&client, "tester@example.com", "testing123").is_none());
+        assert!(login_cookie(&client, "tester@example.com", "changed123").is_some());
+    }
+
+    #[test]
+    fn password_reset_is_single_use() {
+        use crate::mail::{ManagedMailer, MemoryMailer};
+        use crate::store::{ManagedStore, MemoryStore};
+
+        debug!("Resetting a forgotten password through the emailed link");
+        let mailer = MemoryMailer::new();
+        let rocket = rocket::build()
+            .manage::<ManagedStore>(std::sync::Arc::new(MemoryStore::new()))
+            .manage::<ManagedMailer>(std::sync::Arc::new(mailer.clone()))
+            .attach(AuthFairing::new());
+        let client = Client::untracked(rocket).unwrap();
+        ensure_testing_account(&client);
+        let session = login_cookie(&client, "tester@example.com", "testing123").unwrap();
+        // the verification email from creating the account
+        let before = mailer.sent().len();
+
+        // no one gets told if the account exists
+        let res = client
+            .post(uri!(pages::request_password_reset))
+            .header(ContentType::JSON)
+            .body(r#"{ "email": "nobody" }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Accepted);
+
+        let res = client
+            .post(uri!(pages::request_password_reset))
+            .header(ContentType::JSON)
+            .body(r#"{ "email": "tester@example.com" }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Accepted);
+
+        // only the account that exists was sent anything
+        let sent = wait_for_mail(&mailer, before + 1);
+        assert_eq!(sent.len(), before + 1);
+        assert_eq!(sent[before].to, "tester@example.com");
+        let token = sent[before].body
+            .split("token=")
+            .nth(1)
+            .and_then(|rest| rest.split_whitespace().next())
+            .unwrap();
+
+        let reset = |token: &str| client
+            .post(uri!(pages::reset_password))
+            .header(ContentType::JSON)
+            .body(format!(r#"{{ "token": "{token}", "new_password": "reset-password" }}"#))
+            .dispatch()
+            .status();
+        assert_eq!(reset("not a token"), Status::BadRequest);
+        // checked against their email like anywhere else, without using up the token
+        let res = client
+            .post(uri!(pages::reset_password))
+            .header(ContentType::JSON)
+            .body(format!(r#"{{ "token": "{token}", "new_password": "tester1234" }}"#))
+            .dispatch();
+        assert_eq!(res.status(), Status::UnprocessableEntity);
+        assert_eq!(res.into_string().unwrap(), r#"{"errors":{"new_password":["similar_to_email"]}}"#);
+        assert_eq!(reset(token), Status::Accepted);
+        assert_eq!(reset(token), Status::BadRequest);
+
+        // every session is logged out
+        let res = client.get(uri!(pages::login)).private_cookie(session).dispatch();
+        assert_eq!(res.status(), Status::Unauthorized);
+        assert!(login_cookie(&client, "tester@example.com", "testing123").is_none());
+        assert!(login_cookie(&client, "tester@example.com", "reset-password").is_some());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[rocket::async_test]
+    async fn account_db_tokens() {
+        use crate::{config::{AccountBackend, PoolConfig}, db::Account, store::{TokenPurpose, UserStore}, AccountDb, AccountStoreError};
+
+        debug!("Issuing and redeeming tokens in the account database");
+        let path = "test_tokens.sqlite";
+        let _ = std::fs::remove_file(path);
+        let backend = AccountBackend::Sqlite { path: path.to_string() };
+        let accounts = AccountDb::new(&backend, &PoolConfig::default()).unwrap();
+        accounts.run_migrations().await.unwrap();
+
+        let hash = crate::auth::keyring::Keyring::<dyn crate::auth::keyring::KeyStorage>::hash_password("testing123", &Default::default()).unwrap();
+        let user = accounts.create("tokenTester", hash.as_str()).await.unwrap();
+        let purpose = TokenPurpose::PasswordReset;
+
+        // a newer token replaces the older one
+        let old = Account::issue_token(&accounts, user.id, purpose, 60, None).await.unwrap();
+        let new = Account::issue_token(&accounts, user.id, purpose, 60, None).await.unwrap();
+        assert_eq!(Account::peek_token(&accounts, purpose, &old).await.unwrap(), None);
+        // looking doesn't use it up
+        assert_eq!(Account::peek_token(&accounts, purpose, &new).await.unwrap().map(|t| t.account_id), Some(user.id));
+        assert_eq!(Account::redeem_token(&accounts, purpose, &old).await.unwrap(), None);
+        assert_eq!(Account::redeem_token(&accounts, purpose, &new).await.unwrap().map(|t| t.account_id), Some(user.id));
+        assert_eq!(Account::redeem_token(&accounts, purpose, &new).await.unwrap(), None);
+
+        let expired = Account::issue_token(&accounts, user.id, purpose, 0, None).await.unwrap();
+        assert!(!Account::has_token(&accounts, user.id, purpose).await.unwrap());
+        assert_eq!(Account::redeem_token(&accounts, purpose, &expired).await.unwrap(), None);
+
+        // links to revert an email change don't replace each other
+        let revert = TokenPurpose::RevertEmail;
+        let first = Account::issue_token(&accounts, user.id, revert, 60, Some("first@example.com".into())).await.unwrap();
+        let second = Account::issue_token(&accounts, user.id, revert, 60, Some("second@example.com".into())).await.unwrap();
+        assert!(Account::has_token(&accounts, user.id, revert).await.unwrap());
+        assert_eq!(Account::redeem_token(&accounts, revert, &first).await.unwrap().and_then(|t| t.data).as_deref(), Some("first@example.com"));
+        assert_eq!(Account::redeem_token(&accounts, revert, &second).await.unwrap().and_then(|t| t.data).as_deref(), Some("second@example.com"));
+        assert!(!Account::has_token(&accounts, user.id, revert).await.unwrap());
+
+        assert!(matches!(
+            Account::issue_token(&accounts, user.id + 1, purpose, 60, None).await,
+            Err(AccountStoreError::NotFound)
+        ));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn unverified_accounts_turned_away() {
+        use crate::mail::{ManagedMailer, MemoryMailer};
+        use crate::store::{ManagedStore, MemoryStore};
+
+        debug!("Logging in before and after verifying the account's email");
+        let mailer = MemoryMailer::new();
+        let rocket = rocket::build()
+            .manage::<ManagedStore>(std::sync::Arc::new(MemoryStore::new()))
+            .manage::<ManagedMailer>(std::sync::Arc::new(mailer.clone()))
+            .attach(AuthFairing::new().require_verified(true));
+        let client = Client::untracked(rocket).unwrap();
+        ensure_testing_account(&client);
+
+        let res = client
+            .get(uri!(pages::login))
+            .header(Header::new(authentication::USERNAME_HEADER_ID, "tester@example.com"))
+            .header(Header::new(authentication::PASSWORD_HEADER_ID, "testing123"))
+            .dispatch();
+        assert_eq!(res.status(), Status::Forbidden);
+
+        let token = |sent: &[crate::mail::Email], i: usize| sent[i].body
+            .split("token=")
+            .nth(1)
+            .and_then(|rest| rest.split_whitespace().next())
+            .unwrap()
+            .to_string();
+        let sent = mailer.sent();
+        assert_eq!(sent.len(), 1);
+        let first = token(&sent, 0);
+
+        // the link got lost, ask for another one
+        let resend = |email: &str| {
+            let res = client
+                .post(uri!(pages::resend_verification))
+                .header(ContentType::JSON)
+                .body(format!(r#"{{ "email": "{email}" }}"#))
+                .dispatch();
+            assert_eq!(res.status(), Status::Accepted);
+            res.into_string().unwrap()
+        };
+        assert_eq!(resend("nobody@example.com"), resend("tester@example.com"));
+        let sent = wait_for_mail(&mailer, 2);
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[1].to, "tester@example.com");
+        let second = token(&sent, 1);
+
+        let res = client.get(uri!(pages::verify_email("not a token"))).dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+        // only the newest link works
+        let res = client.get(uri!(pages::verify_email(&first))).dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+        let res = client.get(uri!(pages::verify_email(&second))).dispatch();
+        assert_eq!(res.status(), Status::Accepted);
+
+        assert!(login_cookie(&client, "tester@example.com", "testing123").is_some());
+
+        // verified accounts aren't sent any more links
+        resend("tester@example.com");
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        assert_eq!(mailer.sent().len(), 2);
+    }
+
+    #[test]
+    fn email_change_confirmed_and_reverted() {
+        use crate::mail::{ManagedMailer, MemoryMailer};
+        use crate::store::{ManagedStore, MemoryStore};
+
+        fn token(mailer: &MemoryMailer, to: &str) -> String {
+            let sent = mailer.sent();
+            let email = sent.iter().rev().find(|e| e.to == to).unwrap();
+            email.body
+                .split("token=")
+                .nth(1)
+                .and_then(|rest| rest.split_whitespace().next())
+                .unwrap()
+                .to_string()
+        }
+
+        debug!("Changing the account's email, then changing it back from the old address");
+        let mailer = MemoryMailer::new();
+        let rocket = rocket::build()
+            .mount("/", routes![session_email])
+            .manage::<ManagedStore>(std::sync::Arc::new(MemoryStore::new()))
+            .manage::<ManagedMailer>(std::sync::Arc::new(mailer.clone()))
+            .attach(AuthFairing::new());
+        let client = Client::untracked(rocket).unwrap();
+        ensure_testing_account(&client);
+        let session = login_cookie(&client, "tester@example.com", "testing123").unwrap();
+
+        let res = client
+            .post(uri!(pages::change_email))
+            .private_cookie(session.clone())
+            .header(ContentType::JSON)
+            .body(r#"{ "current_password": "testing123", "new_email": "changed@example.com" }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Accepted);
+
+        // nothing changes until the new address is confirmed
+        assert!(login_cookie(&client, "changed@example.com", "testing123").is_none());
+        let confirm = token(&mailer, "changed@example.com");
+        let res = client.get(uri!(pages::confirm_email_change(&confirm))).dispatch();
+        assert_eq!(res.status(), Status::Accepted);
+        let res = client.get(uri!(pages::confirm_email_change(&confirm))).dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+
+        // live sessions know about the new email
+        let res = client.get("/session_email").private_cookie(session.clone()).dispatch();
+        assert_eq!(res.into_string().unwrap(), "changed@example.com");
+        assert!(login_cookie(&client, "tester@example.com", "testing123").is_none());
+        assert!(login_cookie(&client, "changed@example.com", "testing123").is_some());
+
+        // the old address can take it back, logging everyone out
+        let revert = token(&mailer, "tester@example.com");
+        let res = client.get(uri!(pages::revert_email_change(&revert))).dispatch();
+        assert_eq!(res.status(), Status::Accepted);
+        let res = client.get("/session_email").private_cookie(session).dispatch();
+        assert_eq!(res.status(), Status::Unauthorized);
+        assert!(login_cookie(&client, "tester@example.com", "testing123").is_some());
+    }
+
+    #[test]
+    fn email_change_revert_survives_another_change() {
+        use crate::mail::{ManagedMailer, MemoryMailer};
+        use crate::store::{ManagedStore, MemoryStore};
+
+        fn token(mailer: &MemoryMailer, to: &str) -> String {
+            let sent = mailer.sent();
+            let email = sent.iter().rev().find(|e| e.to == to).unwrap();
+            email.body
+                .split("token=")
+                .nth(1)
+                .and_then(|rest| rest.split_whitespace().next())
+                .unwrap()
+                .to_string()
+        }
+
+        debug!("Someone who got into the account changes its email twice, to keep the owner from reverting it");
+        let mailer = MemoryMailer::new();
+        let rocket = rocket::build()
+            .manage::<ManagedStore>(std::sync::Arc::new(MemoryStore::new()))
+            .manage::<ManagedMailer>(std::sync::Arc::new(mailer.clone()))
+            .attach(AuthFairing::new());
+        let client = Client::untracked(rocket).unwrap();
+        ensure_testing_account(&client);
+        let session = login_cookie(&client, "tester@example.com", "testing123").unwrap();
+
+        let change_email = |new_email: &str| client
+            .post(uri!(pages::change_email))
+            .private_cookie(session.clone())
+            .header(ContentType::JSON)
+            .body(format!(r#"{{ "current_password": "testing123", "new_email": "{new_email}" }}"#))
+            .dispatch()
+            .status();
+
+        // A -> B
+        assert_eq!(change_email("attacker@example.com"), Status::Accepted);
+        let confirm = token(&mailer, "attacker@example.com");
+        let res = client.get(uri!(pages::confirm_email_change(&confirm))).dispatch();
+        assert_eq!(res.status(), Status::Accepted);
+
+        // B -> C has to wait until the owner can't revert anymore
+        let sent = mailer.sent().len();
+        assert_eq!(change_email("elsewhere@example.com"), Status::Conflict);
+        assert_eq!(mailer.sent().len(), sent);
+
+        // so the owner's link still takes it back
+        let revert = token(&mailer, "tester@example.com");
+        let res = client.get(uri!(pages::revert_email_change(&revert))).dispatch();
+        assert_eq!(res.status(), Status::Accepted);
+        assert!(login_cookie(&client, "tester@example.com", "testing123").is_some());
+        assert!(login_cookie(&client, "attacker@example.com", "testing123").is_none());
+    }
+
+    #[test]
+    fn delete_account_then_purge() {
+        use std::sync::{Arc, Mutex};
+        use crate::events::{AuthEvent, AuthListener, ManagedListener};
+        use crate::store::{ManagedStore, MemoryStore};
+
+        #[derive(Clone, Default)]
+        struct Recorded(Arc<Mutex<Vec<AuthEvent>>>);
+
+        #[rocket::async_trait]
+        impl AuthListener for Recorded {
+            async fn on_event(&self, event: &AuthEvent) {
+                self.0.lock().unwrap().push(event.clone());
+            }
+        }
+
+        fn delete(client: &Client, session: rocket::http::Cookie<'static>, body: &'static str) -> Status {
+            client
+                .delete(uri!(pages::delete_account))
+                .private_cookie(session)
+                .header(ContentType::JSON)
+                .body(body)
+                .dispatch()
+                .status()
+        }
+
+        debug!("Deleting an account, with and without a grace period");
+        for grace in [60, 0] {
+            let events = Recorded::default();
+            let figment = rocket::Config::figment().merge(("auth.deletion_grace", grace));
+            let rocket = rocket::custom(figment)
+                .manage::<ManagedStore>(std::sync::Arc::new(MemoryStore::new()))
+                .manage::<ManagedListener>(std::sync::Arc::new(events.clone()))
+                .attach(AuthFairing::new());
+            let client = Client::untracked(rocket).unwrap();
+            ensure_testing_account(&client);
+            let first = login_cookie(&client, "tester@example.com", "testing123").unwrap();
+            let second = login_cookie(&client, "tester@example.com", "testing123").unwrap();
+
+            assert_eq!(delete(&client, first.clone(), r#"{ "current_password": "nope" }"#), Status::Unauthorized);
+            assert_eq!(delete(&client, first.clone(), r#"{ "current_password": "testing123" }"#), Status::Accepted);
+
+            // every session is gone, and it can't be logged into again
+            for session in [first, second] {
+                let res = client.get(uri!(pages::login)).private_cookie(session).dispatch();
+                assert_eq!(res.status(), Status::Unauthorized);
+            }
+            assert!(login_cookie(&client, "tester@example.com", "testing123").is_none());
+
+            let events = events.0.lock().unwrap().clone();
+            assert!(matches!(events[0], AuthEvent::AccountDeleted { account_id: 1, .. }));
+            let purged = events.iter().any(|e| matches!(e, AuthEvent::AccountPurged { account_id: 1, .. }));
+            let res = client
+                .post(uri!(pages::create_account))
+                .header(ContentType::JSON)
+                .body(r#"{ "name": "tester@example.com", "password": "testing123" }"#)
+                .dispatch();
+            if grace == 0 {
+                // gone for good, so the email is free again
+                assert!(purged);
+                assert_eq!(res.status(), Status::Accepted);
+            } else {
+                assert!(!purged);
+                assert_eq!(res.status(), Status::Conflict);
+            }
+        }
+    }
+
+    #[test]
+    fn deleted_accounts_purged_while_running() {
+        use std::sync::{Arc, Mutex};
+        use crate::events::{AuthEvent, AuthListener, ManagedListener};
+        use crate::store::{ManagedStore, MemoryStore};
+
+        #[derive(Clone, Default)]
+        struct Recorded(Arc<Mutex<Vec<AuthEvent>>>);
+
+        #[rocket::async_trait]
+        impl AuthListener for Recorded {
+            async fn on_event(&self, event: &AuthEvent) {
+                self.0.lock().unwrap().push(event.clone());
+            }
+        }
+
+        debug!("Purging a deleted account once its grace period is over, without restarting");
+        let events = Recorded::default();
+        let figment = rocket::Config::figment()
+            .merge(("auth.deletion_grace", 1))
+            .merge(("auth.purge_interval", 1));
+        let rocket = rocket::custom(figment)
+            .manage::<ManagedStore>(Arc::new(MemoryStore::new()))
+            .manage::<ManagedListener>(Arc::new(events.clone()))
+            .attach(AuthFairing::new());
+        let client = Client::untracked(rocket).unwrap();
+        ensure_testing_account(&client);
+        let session = login_cookie(&client, "tester@example.com", "testing123").unwrap();
+        let res = client
+            .delete(uri!(pages::delete_account))
+            .private_cookie(session)
+            .header(ContentType::JSON)
+            .body(r#"{ "current_password": "testing123" }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Accepted);
+
+        let purged = || events.0.lock().unwrap().iter().any(|e| matches!(e, AuthEvent::AccountPurged { account_id: 1, .. }));
+        assert!(!purged());
+        for _ in 0..50 {
+            if purged() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        assert!(purged());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[rocket::async_test]
+    async fn account_db_purges_deleted() {
+        use crate::{config::{AccountBackend, PoolConfig}, store::{TokenPurpose, UserStore}, Account, AccountDb};
+
+        debug!("Soft-deleting and purging accounts in the account database");
+        let path = "test_purge.sqlite";
+        let _ = std::fs::remove_file(path);
+        let backend = AccountBackend::Sqlite { path: path.to_string() };
+        let accounts = AccountDb::new(&backend, &PoolConfig::default()).unwrap();
+        accounts.run_migrations().await.unwrap();
+
+        let hash = crate::auth::keyring::Keyring::<dyn crate::auth::keyring::KeyStorage>::hash_password("testing123", &Default::default()).unwrap();
+        let deleted = accounts.create("deleted", hash.as_str()).await.unwrap();
+        let kept = accounts.create("kept", hash.as_str()).await.unwrap();
+        let token = Account::issue_token(&accounts, deleted.id, TokenPurpose::PasswordReset, 60, None).await.unwrap();
+
+        let login = crate::store::LoginRecord { at: 50, ip: Some("127.0.0.1".to_string()), user_agent: None };
+        accounts.record_login(deleted.id, login.clone()).await.unwrap();
+        accounts.record_login(kept.id, login.clone()).await.unwrap();
+        assert_eq!(accounts.login_history(deleted.id).await.unwrap(), vec![login.clone()]);
+
+        accounts.set_status(kept.id, crate::store::AccountStatus::PendingApproval).await.unwrap();
+        accounts.set_expiry(kept.id, Some(200)).await.unwrap();
+        let found = accounts.find_by_id(kept.id).await.unwrap().unwrap();
+        assert_eq!((found.status, found.expires_at), (crate::store::AccountStatus::PendingApproval, Some(200)));
+
+        accounts.mark_deleted(deleted.id, 100).await.unwrap();
+        assert_eq!(accounts.find_by_id(deleted.id).await.unwrap().unwrap().deleted_at, Some(100));
+        assert!(accounts.purge_deleted(99).await.unwrap().is_empty());
+
+        let purged = accounts.purge_deleted(100).await.unwrap();
+        assert_eq!(purged.len(), 1);
+        assert_eq!(purged[0].login, "deleted");
+        assert!(accounts.find_by_id(deleted.id).await.unwrap().is_none());
+        assert!(accounts.find_by_id(kept.id).await.unwrap().is_some());
+        // their tokens and history went with them
+        assert_eq!(Account::redeem_token(&accounts, TokenPurpose::PasswordReset, &token).await.unwrap(), None);
+        assert!(accounts.login_history(deleted.id).await.unwrap().is_empty());
+        assert_eq!(accounts.login_history(kept.id).await.unwrap(), vec![login]);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn export_account_data() {
+        use rocket::serde::json::Value;
+        use crate::store::{ManagedStore, MemoryStore};
+
+        debug!("Exporting everything known about an account");
+        let rocket = rocket::build()
+            .manage::<ManagedStore>(std::sync::Arc::new(MemoryStore::new()))
+            .attach(AuthFairing::new());
+        let client = Client::untracked(rocket).unwrap();
+        ensure_testing_account(&client);
+
+        let mut sessions = Vec::new();
+        for agent in ["first agent", "second agent"] {
+            let res = client
+                .get(uri!(pages::login))
+                .header(Header::new(authentication::USERNAME_HEADER_ID, "tester@example.com"))
+                .header(Header::new(authentication::PASSWORD_HEADER_ID, "testing123"))
+                .header(Header::new("User-Agent", agent))
+                .dispatch();
+            sessions.push(res.cookies().get_private(authentication::SESSION_COOKIE_ID).unwrap());
+        }
+
+        let res = client.get(uri!(pages::export_account)).private_cookie(sessions[1].clone()).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let body = res.into_string().unwrap();
+        assert!(!body.contains("password_hash"));
+        assert!(!body.contains(sessions[0].value()));
+
+        let export: Value = rocket::serde::json::from_str(&body).unwrap();
+        assert_eq!(export["email"], "tester@example.com");
+        assert_eq!(export["login_history"].as_array().unwrap().len(), 2);
+        assert_eq!(export["login_history"][0]["user_agent"], "first agent");
+
+        let exported = export["sessions"].as_array().unwrap();
+        assert_eq!(exported.len(), 2);
+        let current = exported.iter().filter(|s| s["current"] == true).collect::<Vec<_>>();
+        assert_eq!(current.len(), 1);
+        assert_eq!(current[0]["user_agent"], "second agent");
+    }
+
+    #[rocket::async_test]
+    async fn account_states_enforced() {
+        use rocket::local::asynchronous::Client;
+        use crate::store::{AccountStatus, ManagedStore, MemoryStore};
+
+        debug!("Locking, disabling and expiring an account, with and without a session");
+        let rocket = rocket::build()
+            .manage::<ManagedStore>(std::sync::Arc::new(MemoryStore::new()))
+            .attach(AuthFairing::new());
+        let client = Client::untracked(rocket).await.unwrap();
+        let res = client
+            .post(uri!(pages::create_account))
+            .header(ContentType::JSON)
+            .body(r#"{ "name": "tester@example.com", "password": "testing123" }"#)
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::Accepted);
+
+        let login = |password: &'static str| client
+            .get(uri!(pages::login))
+            .header(Header::new(authentication::USERNAME_HEADER_ID, "tester@example.com"))
+            .header(Header::new(authentication::PASSWORD_HEADER_ID, password))
+            .dispatch();
+        let res = login("testing123").await;
+        let session = res.cookies().get_private(authentication::SESSION_COOKIE_ID).unwrap();
+        let with_session = || client.get(uri!(pages::login)).private_cookie(session.clone()).dispatch();
+
+        let accounts = client.rocket().state::<ManagedStore>().unwrap();
+        let id = accounts.find_by_login("tester@example.com").await.unwrap().unwrap().id;
+
+        for (status, expected) in [
+            (AccountStatus::Locked, Status::Locked),
+            (AccountStatus::Disabled, Status::Forbidden),
+            (AccountStatus::PendingApproval, Status::Forbidden),
+        ] {
+            accounts.set_status(id, status).await.unwrap();
+            assert_eq!(login("testing123").await.status(), expected);
+            assert_eq!(with_session().await.status(), expected);
+            // the state isn't given away without the password
+            assert_eq!(login("wrong").await.status(), Status::Unauthorized);
+        }
+
+        accounts.set_status(id, AccountStatus::Active).await.unwrap();
+        accounts.set_expiry(id, Some(crate::now() + 60)).await.unwrap();
+        assert_eq!(with_session().await.status(), Status::Accepted);
+        accounts.set_expiry(id, Some(crate::now())).await.unwrap();
+        assert_eq!(login("testing123").await.status(), Status::Forbidden);
+        assert_eq!(with_session().await.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn normalized_logins() {
+        use crate::identifier::normalize;
+        use crate::store::{ManagedStore, MemoryStore};
+
+        debug!("Logins match no matter their case, width or domain encoding");
+        assert_eq!(normalize("  Alice@Example.COM\t"), "alice@example.com");
+        assert_eq!(normalize("ａｌｉｃｅ＠ｅｘａｍｐｌｅ.com"), "alice@example.com");
+        assert_eq!(normalize("Straße"), "strasse");
+        assert_eq!(normalize("bob@BÜCHER.example"), normalize("bob@xn--bcher-kva.example"));
+
+        let rocket = rocket::build()
+            .manage::<ManagedStore>(std::sync::Arc::new(MemoryStore::new()))
+            .attach(AuthFairing::new());
+        let client = Client::untracked(rocket).unwrap();
+        let create = |name: &str| client
+            .post(uri!(pages::create_account))
+            .header(ContentType::JSON)
+            .body(format!(r#"{{ "name": "{name}", "password": "testing123" }}"#))
+            .dispatch()
+            .status();
+
+        assert_eq!(create("Alice@Example.com"), Status::Accepted);
+        assert_eq!(create(" alice@EXAMPLE.com"), Status::Conflict);
+        assert_eq!(create("ａｌｉｃｅ@example.com"), Status::Conflict);
+        assert!(login_cookie(&client, "ALICE@example.COM ", "testing123").is_some());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[rocket::async_test]
+    async fn account_db_login_collisions() {
+        use diesel::{Connection, RunQueryDsl, SqliteConnection};
+        use crate::{config::{AccountBackend, PoolConfig}, store::UserStore, AccountDb, AccountStoreError};
+
+        debug!("Filling in the normalised logins of accounts made before them");
+        let path = "test_collisions.sqlite";
+        let _ = std::fs::remove_file(path);
+        let backend = AccountBackend::Sqlite { path: path.to_string() };
+        let accounts = AccountDb::new(&backend, &PoolConfig::default()).unwrap();
+        accounts.run_migrations().await.unwrap();
+
+        let hash = crate::auth::keyring::Keyring::<dyn crate::auth::keyring::KeyStorage>::hash_password("testing123", &Default::default()).unwrap();
+        let mut conn = SqliteConnection::establish(path).unwrap();
+        for old in ["Carol@example.com", "carol@EXAMPLE.com", "Dave@example.com"] {
+            diesel::sql_query(format!("INSERT INTO account (email, password_hash) VALUES ('{old}', '{}')", hash.as_str()))
+                .execute(&mut conn)
+                .unwrap();
+        }
+
+        let collisions = accounts.normalize_logins().await.unwrap();
+        assert_eq!(collisions.len(), 1);
+        assert_eq!(collisions[0].key, "carol@example.com");
+        assert_eq!(collisions[0].accounts.len(), 2);
+        // nothing changes the second time around
+        assert_eq!(accounts.normalize_logins().await.unwrap(), collisions);
+
+        assert_eq!(accounts.find_by_login("DAVE@example.com").await.unwrap().unwrap().login, "Dave@example.com");
+        // the oldest gets the login, the other is left for someone to sort out
+        assert_eq!(accounts.find_by_login("carol@EXAMPLE.com").await.unwrap().unwrap().login, "Carol@example.com");
+        let (other, _) = collisions[0].accounts.iter().find(|(_, email)| email == "carol@EXAMPLE.com").unwrap();
+        assert!(accounts.find_by_id(*other).await.unwrap().is_some());
+        assert!(matches!(accounts.create("carol@example.COM", hash.as_str()).await, Err(AccountStoreError::Conflict)));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn signup_validated() {
+        use rocket::serde::json::{json, Value};
+        use crate::store::{ManagedStore, MemoryStore};
+
+        debug!("Creating accounts with bad input, getting told what's wrong with it");
+        let rocket = rocket::build()
+            .manage::<ManagedStore>(std::sync::Arc::new(MemoryStore::new()))
+            .attach(AuthFairing::new());
+        let client = Client::untracked(rocket).unwrap();
+        let create = |body: &str| {
+            let res = client
+                .post(uri!(pages::create_account))
+                .header(ContentType::JSON)
+                .body(body)
+                .dispatch();
+            (res.status(), res.into_json::<Value>())
+        };
+
+        let (status, errors) = create(r#"{ "name": "not an email", "password": "short" }"#);
+        assert_eq!(status, Status::UnprocessableEntity);
+        assert_eq!(errors.unwrap(), json!({ "errors": {
+            "name": ["invalid_characters", "invalid_email"],
+            "password": ["too_short"],
+        }}));
+
+        let (status, errors) = create(r#"{ "name": " ", "password": "long enough" }"#);
+        assert_eq!(status, Status::UnprocessableEntity);
+        assert_eq!(errors.unwrap(), json!({ "errors": { "name": ["required"] }}));
+
+        for bad in ["@example.com", "user@", "user@localhost", "user@exa_mple.com", "us..er@example.com", "a(b)@example.com"] {
+            let (status, _) = create(&format!(r#"{{ "name": "{bad}", "password": "long enough" }}"#));
+            assert_eq!(status, Status::UnprocessableEntity, "{bad}");
+        }
+        let (status, _) = create(&format!(r#"{{ "name": "someone@example.com", "password": "{}" }}"#, "a".repeat(129)));
+        assert_eq!(status, Status::UnprocessableEntity);
+
+        let (status, _) = create(r#"{ "name": " someone@bücher.example ", "password": "long enough" }"#);
+        assert_eq!(status, Status::Accepted);
+
+        debug!("Other routes taking credentials check them the same way");
+        let session = login_cookie(&client, "someone@bücher.example", "long enough").unwrap();
+        let res = client
+            .post(uri!(pages::change_password))
+            .private_cookie(session.clone())
+            .header(ContentType::JSON)
+            .body(r#"{ "current_password": "long enough", "new_password": "" }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::UnprocessableEntity);
+        assert_eq!(res.into_json::<Value>().unwrap()["errors"]["new_password"], json!(["required"]));
+        let res = client
+            .post(uri!(pages::change_email))
+            .private_cookie(session)
+            .header(ContentType::JSON)
+            .body(r#"{ "current_password": "long enough", "new_email": "nowhere" }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::UnprocessableEntity);
+        assert_eq!(res.into_json::<Value>().unwrap()["errors"]["new_email"], json!(["invalid_email"]));
+    }
+
+    #[test]
+    fn breached_list_searched() {
+        use sha1::{Digest, Sha1};
+        use crate::password::is_breached;
+
+        debug!("Binary searching a breached password list on disk");
+        let path = std::env::temp_dir().join(format!("breached_{}.txt", std::process::id()));
+        let breached = (0..500).map(|i| format!("breached{i}")).collect::<Vec<_>>();
+        let mut lines = breached
+            .iter()
+            .enumerate()
+            .map(|(count, password)| format!("{}:{}", hex::encode_upper(Sha1::digest(password)), count + 1))
+            .collect::<Vec<_>>();
+        lines.sort();
+        std::fs::write(&path, lines.join("\r\n") + "\r\n").unwrap();
+
+        for password in &breached {
+            assert!(is_breached(&path, password).unwrap(), "{password}");
+        }
+        for password in ["breached500", "not breached", ""] {
+            assert!(!is_breached(&path, password).unwrap(), "{password}");
+        }
+        std::fs::write(&path, "").unwrap();
+        assert!(!is_breached(&path, "breached0").unwrap());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn password_policy_enforced() {
+        use rocket::serde::json::Value;
+        use sha1::{Digest, Sha1};
+        use crate::store::{ManagedStore, MemoryStore};
+
+        debug!("New passwords have to follow the configured policy");
+        let path = std::env::temp_dir().join(format!("policy_breached_{}.txt", std::process::id()));
+        let mut lines = ["Tr0ub4dor&3", "correct horse battery staple"]
+            .map(|password| format!("{}:42", hex::encode_upper(Sha1::digest(password))));
+        lines.sort();
+        std::fs::write(&path, lines.join("\n")).unwrap();
+
+        let figment = rocket::Config::figment()
+            .merge(("auth.password.min_length", 10))
+            .merge(("auth.password.require_digit", true))
+            .merge(("auth.password.deny_list", ["letmein123"]))
+            .merge(("auth.password.breached_list", path.to_str().unwrap()));
+        let rocket = rocket::custom(figment)
+            .manage::<ManagedStore>(std::sync::Arc::new(MemoryStore::new()))
+            .attach(AuthFairing::new());
+        let client = Client::untracked(rocket).unwrap();
+        let create = |password: &str| {
+            let res = client
+                .post(uri!(pages::create_account))
+                .header(ContentType::JSON)
+                .body(format!(r#"{{ "name": "policy@example.com", "password": "{password}" }}"#))
+                .dispatch();
+            let status = res.status();
+            let errors = res.into_json::<Value>().map(|errors| errors["errors"]["password"].clone());
+            (status, errors)
+        };
+
+        let rejected = [
+            ("short1", "too_short"),
+            ("long enough but no digits", "missing_digit"),
+            ("LetMeIn123", "common"),
+            ("policy@example.com1", "similar_to_email"),
+            ("mypolicy1234", "similar_to_email"),
+            ("Tr0ub4dor&3", "breached"),
+        ];
+        for (password, code) in rejected {
+            let (status, errors) = create(password);
+            assert_eq!(status, Status::UnprocessableEntity, "{password}");
+            assert_eq!(errors.unwrap(), rocket::serde::json::json!([code]), "{password}");
+        }
+        assert_eq!(create("correct horse battery staple").1.unwrap(), rocket::serde::json::json!(["missing_digit"]));
+        assert_eq!(create("a fine 2nd password").0, Status::Accepted);
+
+        debug!("Changing the password is screened as well");
+        let session = login_cookie(&client, "policy@example.com", "a fine 2nd password").unwrap();
+        let change = |new_password: &str| client
+            .post(uri!(pages::change_password))
+            .private_cookie(session.clone())
+            .header(ContentType::JSON)
+            .body(format!(r#"{{ "current_password": "a fine 2nd password", "new_password": "{new_password}" }}"#))
+            .dispatch()
+            .status();
+        assert_eq!(change("Tr0ub4dor&3"), Status::UnprocessableEntity);
+        assert_eq!(change("policy1234567"), Status::UnprocessableEntity);
+
+        let res = client
+            .post(uri!(pages::reset_password))
+            .header(ContentType::JSON)
+            .body(r#"{ "token": "whatever", "new_password": "Tr0ub4dor&3" }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::UnprocessableEntity);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn password_strength_estimated() {
+        use rocket::serde::json::Value;
+        use crate::store::{ManagedStore, MemoryStore};
+
+        debug!("Getting feedback on passwords before signing up with them");
+        let figment = rocket::Config::figment().merge(("auth.password.deny_list", ["hogwarts"]));
+        let rocket = rocket::custom(figment)
+            .manage::<ManagedStore>(std::sync::Arc::new(MemoryStore::new()))
+            .attach(AuthFairing::new());
+        let client = Client::untracked(rocket).unwrap();
+        let strength = |body: &str| client
+            .post(uri!(pages::password_strength))
+            .header(ContentType::JSON)
+            .body(body)
+            .dispatch()
+            .into_json::<Value>()
+            .unwrap();
+
+        // patterns are found, and don't count for much
+        for weak in ["qwerty123", "P@ssw0rd", "aaaaaaaaaa", "abcdefgh1234", "asdfghjkl;", "H0gw4rts!"] {
+            let estimate = strength(&format!(r#"{{ "password": "{weak}" }}"#));
+            assert!(estimate["score"].as_u64().unwrap() <= 1, "{weak}: {estimate}");
+            assert!(!estimate["suggestions"].as_array().unwrap().is_empty(), "{weak}");
+        }
+        let estimate = strength(r#"{ "password": "zxcvbnm12" }"#);
+        assert!(estimate["suggestions"].as_array().unwrap().iter().any(|s| s.as_str().unwrap().contains("qwerty")));
+
+        let estimate = strength(r#"{ "password": "gravel sonnet wander 48" }"#);
+        assert_eq!(estimate["score"], 4);
+        assert_eq!(estimate["acceptable"], true);
+        assert_eq!(estimate["errors"], rocket::serde::json::json!([]));
+
+        // what the policy has against it is there too, with the same codes as signing up
+        let estimate = strength(r#"{ "password": "hogwarts" }"#);
+        assert_eq!(estimate["acceptable"], false);
+        assert_eq!(estimate["score"], 0);
+        assert_eq!(estimate["errors"], rocket::serde::json::json!(["common"]));
+        let estimate = strength(r#"{ "password": "harry potter the third", "email": "harry@hogwarts.example" }"#);
+        assert_eq!(estimate["errors"], rocket::serde::json::json!(["similar_to_email"]));
+
+        // too long is all that's said about long passwords, and really long ones aren't read
+        let estimate = strength(&format!(r#"{{ "password": "{}" }}"#, "hogwarts".repeat(1000)));
+        assert_eq!(estimate["errors"], rocket::serde::json::json!(["too_long"]));
+        assert_eq!(estimate["acceptable"], false);
+        let res = client
+            .post(uri!(pages::password_strength))
+            .header(ContentType::JSON)
+            .body(format!(r#"{{ "password": "{}" }}"#, "hogwarts".repeat(10_000)))
+            .dispatch();
+        assert_eq!(res.status(), Status::PayloadTooLarge);
+        let res = client.post(uri!(pages::password_strength)).header(ContentType::JSON).body("{").dispatch();
+        assert_eq!(res.status(), Status::UnprocessableEntity);
+
+        let signup = client
+            .post(uri!(pages::create_account))
+            .header(ContentType::JSON)
+            .body(r#"{ "name": "harry@hogwarts.example", "password": "harry potter the third" }"#)
+            .dispatch();
+        assert_eq!(signup.status(), Status::UnprocessableEntity);
+    }
+
+    #[rocket::async_test]
+    async fn outdated_hashes_upgraded_on_login() {
+        use argon2::{password_hash::{rand_core::OsRng, PasswordHasher, SaltString}, Algorithm, Argon2, Params, Version};
+        use rocket::local::asynchronous::Client;
+        use crate::password::Hasher;
+        use crate::store::{ManagedStore, MemoryStore};
+
+        debug!("Logging in with passwords hashed the old way, getting them rehashed");
+        let rocket = rocket::build()
+            .manage::<ManagedStore>(std::sync::Arc::new(MemoryStore::new()))
+            .attach(AuthFairing::new());
+        let client = Client::untracked(rocket).await.unwrap();
+        let accounts = client.rocket().state::<ManagedStore>().unwrap();
+
+        let salt = SaltString::generate(OsRng);
+        let weak = Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::new(1024, 1, 1, None).unwrap());
+        let argon2i = Argon2::new(Algorithm::Argon2i, Version::V0x13, Params::default());
+        let old_hashes = [
+            weak.hash_password(b"old and weak", &salt).unwrap().to_string(),
+            argon2i.hash_password(b"old and weak", &salt).unwrap().to_string(),
+        ];
+        for (i, hash) in old_hashes.into_iter().enumerate() {
+            assert!(Hasher::default().needs_rehash(&hash));
+            let email = format!("old{i}@example.com");
+            accounts.create(&email, &hash).await.unwrap();
+
+            let login = |password: &'static str| client
+                .get(uri!(pages::login))
+                .header(Header::new(authentication::USERNAME_HEADER_ID, email.clone()))
+                .header(Header::new(authentication::PASSWORD_HEADER_ID, password))
+                .dispatch();
+
+            // a wrong password doesn't touch it
+            assert_eq!(login("wrong").await.status(), Status::Unauthorized);
+            assert_eq!(accounts.find_by_login(&email).await.unwrap().unwrap().password_hash, hash);
+
+            assert_eq!(login("old and weak").await.status(), Status::Accepted);
+            let upgraded = accounts.find_by_login(&email).await.unwrap().unwrap().password_hash;
+            assert_ne!(upgraded, hash);
+            assert!(!Hasher::default().needs_rehash(&upgraded));
+
+            // and stays the same from then on
+            assert_eq!(login("old and weak").await.status(), Status::Accepted);
+            assert_eq!(accounts.find_by_login(&email).await.unwrap().unwrap().password_hash, upgraded);
+        }
+
+        let current = Hasher::default().hash("testing123").unwrap();
+        assert!(!Hasher::default().needs_rehash(current.as_str()));
+    }
+
+    #[rocket::async_test]
+    async fn hashing_costs_from_config() {
+        use argon2::{Params, PasswordHash};
+        use rocket::local::asynchronous::Client;
+        use crate::auth::keyring::{KeyStorage, Keyring};
+        use crate::config::HashingConfig;
+        use crate::password::{calibrate, Hasher};
+        use crate::store::{ManagedStore, MemoryStore};
+
+        debug!("Hashing with the costs from the config, and redoing hashes when they change");
+        let figment = rocket::Config::figment()
+            .merge(("auth.hashing.memory_cost", 1024))
+            .merge(("auth.hashing.iterations", 1));
+        let rocket = rocket::custom(figment)
+            .manage::<ManagedStore>(std::sync::Arc::new(MemoryStore::new()))
+            .attach(AuthFairing::new());
+        let client = Client::untracked(rocket).await.unwrap();
+        let res = client
+            .post(uri!(pages::create_account))
+            .header(ContentType::JSON)
+            .body(r#"{ "name": "cheap@example.com", "password": "testing123" }"#)
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::Accepted);
+
+        let accounts = client.rocket().state::<ManagedStore>().unwrap();
+        let costs = |hash: String| {
+            let params = Params::try_from(&PasswordHash::new(&hash).unwrap()).unwrap();
+            (params.m_cost(), params.t_cost(), params.p_cost())
+        };
+        let user = accounts.find_by_login("cheap@example.com").await.unwrap().unwrap();
+        assert_eq!(costs(user.password_hash), (1024, 1, 1));
+
+        let pricier = Hasher::new(HashingConfig { memory_cost: 2048, iterations: 2, parallelism: 2 }).unwrap();
+        let user = Keyring::<dyn KeyStorage>::check_credentials(accounts.as_ref(), &pricier, "cheap@example.com", "testing123").await.unwrap();
+        assert_eq!(costs(user.password_hash), (2048, 2, 2));
+        let user = accounts.find_by_login("cheap@example.com").await.unwrap().unwrap();
+        assert_eq!(costs(user.password_hash), (2048, 2, 2));
+
+        let calibration = calibrate(std::time::Duration::from_millis(20), 1024, 1).unwrap();
+        assert!(calibration.took >= std::time::Duration::from_millis(20));
+        assert_eq!((calibration.hashing.memory_cost, calibration.hashing.parallelism), (1024, 1));
+        assert!(calibrate(std::time::Duration::from_millis(20), 1, 1).is_err());
+    }
+
+    #[rocket::async_test]
+    async fn peppered_hashes_rotated() {
+        use argon2::{Params, PasswordHash};
+        use rocket::local::asynchronous::Client;
+        use crate::auth::keyring::{KeyStorage, Keyring};
+        use crate::password::Hasher;
+        use crate::store::{ManagedStore, MemoryStore};
+
+        debug!("Peppering hashes with a secret from a file, then rotating it");
+        let path = std::env::temp_dir().join(format!("pepper_{}", std::process::id()));
+        std::fs::write(&path, "the old pepper, from a file\n").unwrap();
+        let file = path.to_str().unwrap().to_string();
+
+        let fails_ignite = |pepper: &[(&str, &str)]| {
+            let figment = pepper.iter().fold(rocket::Config::figment(), |figment, (key, value)| {
+                figment.merge((format!("auth.pepper.{key}"), value))
+            });
+            let rocket = rocket::custom(figment).manage::<ManagedStore>(std::sync::Arc::new(MemoryStore::new())).attach(AuthFairing::new());
+            // rocket's errors panic if dropped without being looked at
+            async move { matches!(Client::untracked(rocket).await, Err(e) if matches!(e.kind(), rocket::error::ErrorKind::FailedFairings(_))) }
+        };
+        assert!(fails_ignite(&[("current", "missing")]).await);
+        assert!(fails_ignite(&[("keys.toolongkeyid.secret", "long enough to be a pepper")]).await);
+        assert!(fails_ignite(&[("keys.short.secret", "too short")]).await);
+        assert!(fails_ignite(&[("keys.nofile.file", "no/such/pepper")]).await);
+
+        let figment = rocket::Config::figment()
+            .merge(("auth.pepper.current", "old"))
+            .merge(("auth.pepper.keys.old.file", &file));
+        let rocket = rocket::custom(figment)
+            .manage::<ManagedStore>(std::sync::Arc::new(MemoryStore::new()))
+            .attach(AuthFairing::new());
+        let client = Client::untracked(rocket).await.unwrap();
+        let res = client
+            .post(uri!(pages::create_account))
+            .header(ContentType::JSON)
+            .body(r#"{ "name": "peppered@example.com", "password": "testing123" }"#)
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::Accepted);
+
+        let accounts = client.rocket().state::<ManagedStore>().unwrap();
+        let key_id = |hash: &str| Params::try_from(&PasswordHash::new(hash).unwrap()).unwrap().keyid().to_vec();
+        let peppered = accounts.find_by_login("peppered@example.com").await.unwrap().unwrap().password_hash;
+        assert_eq!(key_id(&peppered), b"old");
+        // the table alone isn't enough
+        assert!(!Hasher::default().verify("testing123", &peppered));
+        let res = client
+            .get(uri!(pages::login))
+            .header(Header::new(authentication::USERNAME_HEADER_ID, "peppered@example.com"))
+            .header(Header::new(authentication::PASSWORD_HEADER_ID, "testing123"))
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::Accepted);
+
+        debug!("Rotating re-peppers on the next login");
+        let rotated = Hasher::default()
+            .with_old_pepper("old", "the old pepper, from a file").unwrap()
+            .with_pepper("new", "a brand new pepper").unwrap();
+        assert!(rotated.needs_rehash(&peppered));
+        Keyring::<dyn KeyStorage>::check_credentials(accounts.as_ref(), &rotated, "peppered@example.com", "testing123").await.unwrap();
+        let repeppered = accounts.find_by_login("peppered@example.com").await.unwrap().unwrap().password_hash;
+        assert_eq!(key_id(&repeppered), b"new");
+        assert!(!rotated.needs_rehash(&repeppered));
+
+        // now the old one can go
+        let new_only = Hasher::default().with_pepper("new", "a brand new pepper").unwrap();
+        assert!(new_only.verify("testing123", &repeppered));
+        assert!(!new_only.verify("testing123", &peppered));
+
+        // unpeppered hashes get peppered too
+        let plain = Hasher::default().hash("testing123").unwrap();
+        assert!(new_only.verify("testing123", plain.as_str()));
+        assert!(new_only.needs_rehash(plain.as_str()));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[rocket::async_test]
+    async fn legacy_hashes_imported() {
+        use argon2::password_hash::{rand_core::OsRng, PasswordHasher, SaltString};
+        use crate::{auth::{authentication::LoginError, keyring::{KeyStorage, Keyring}}, config::{AccountBackend, PoolConfig}, db::Account, password::Hasher, store::UserStore, AccountDb, AccountStoreError};
+
+        debug!("Importing accounts with bcrypt, PBKDF2 and scrypt hashes, upgrading them on login");
+        let path = "test_legacy.sqlite";
+        let _ = std::fs::remove_file(path);
+        let backend = AccountBackend::Sqlite { path: path.to_string() };
+        let accounts = AccountDb::new(&backend, &PoolConfig::default()).unwrap();
+        accounts.run_migrations().await.unwrap();
+
+        let salt = SaltString::generate(OsRng);
+        let pbkdf2_params = pbkdf2::Params { rounds: 1000, output_length: 32 };
+        let scrypt_params = scrypt::Params::new(8, 8, 1, 32).unwrap();
+        let legacy = [
+            ("bcrypt@example.com", bcrypt::hash("from the old system", 4).unwrap()),
+            // what passlib and friends make, with the older prefix
+            ("bcrypt2a@example.com", bcrypt::hash_with_result("from the old system", 4).unwrap().format_for_version(bcrypt::Version::TwoA)),
+            ("pbkdf2@example.com", pbkdf2::Pbkdf2.hash_password_customized(b"from the old system", Some(pbkdf2::Algorithm::Pbkdf2Sha256.ident()), None, pbkdf2_params, &salt).unwrap().to_string()),
+            ("scrypt@example.com", scrypt::Scrypt.hash_password_customized(b"from the old system", None, None, scrypt_params, &salt).unwrap().to_string()),
+        ];
+
+        let hasher = Hasher::default();
+        for (login, hash) in &legacy {
+            assert!(hasher.needs_rehash(hash));
+            let imported = Account::import(&accounts, login, hash, &hasher).await.unwrap();
+            assert_eq!(&imported.password_hash, hash);
+
+            // a wrong password doesn't touch it
+            let wrong = Keyring::<dyn KeyStorage>::check_credentials(&accounts, &hasher, login, "wrong").await;
+            assert!(matches!(wrong, Err(LoginError::WrongPassword)));
+            assert_eq!(&accounts.find_by_login(login).await.unwrap().unwrap().password_hash, hash);
+
+            Keyring::<dyn KeyStorage>::check_credentials(&accounts, &hasher, login, "from the old system").await.unwrap();
+            let upgraded = accounts.find_by_login(login).await.unwrap().unwrap().password_hash;
+            assert!(upgraded.starts_with("$argon2id$"));
+            assert!(!hasher.needs_rehash(&upgraded));
+            Keyring::<dyn KeyStorage>::check_credentials(&accounts, &hasher, login, "from the old system").await.unwrap();
+        }
+
+        // nothing that can't be verified gets in
+        for unknown in ["5f4dcc3b5aa765d61d8327deb882cf99", "$1$saltsalt$2vnaRpHa6Jxjz5n83ok8Z0", "$2b$04$tooshort"] {
+            assert!(matches!(Account::import(&accounts, "unknown@example.com", unknown, &hasher).await, Err(AccountStoreError::Hash(_))));
+        }
+        assert!(accounts.find_by_login("unknown@example.com").await.unwrap().is_none());
+
+        debug!("Plugging in another format");
+        struct Plain;
+        impl crate::password::LegacyHash for Plain {
+            fn name(&self) -> &'static str {
+                "plain"
+            }
+            fn recognizes(&self, stored_hash: &str) -> bool {
+                stored_hash.starts_with("plain:")
+            }
+            fn verify(&self, password: &str, stored_hash: &str) -> bool {
+                stored_hash.strip_prefix("plain:") == Some(password)
+            }
+        }
+        assert!(Account::import(&accounts, "plain@example.com", "plain:hunter22", &hasher).await.is_err());
+        let hasher = hasher.with_legacy(Plain);
+        Account::import(&accounts, "plain@example.com", "plain:hunter22", &hasher).await.unwrap();
+        Keyring::<dyn KeyStorage>::check_credentials(&accounts, &hasher, "plain@example.com", "hunter22").await.unwrap();
+        assert!(accounts.find_by_login("plain@example.com").await.unwrap().unwrap().password_hash.starts_with("$argon2id$"));
+        let _ = std::fs::remove_file(path);
+    }
 }
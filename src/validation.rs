@@ -0,0 +1,175 @@
+use std::collections::BTreeMap;
+
+use rocket::{http::Status, response::{self, Responder}, serde::json::Json, tokio::task::spawn_blocking, Request};
+use serde::Serialize;
+use tracing::*;
+
+use crate::config::PasswordPolicy;
+use crate::password;
+
+/// Longest email that can be delivered to (RFC 5321).
+pub const MAX_EMAIL_LENGTH: usize = 254;
+
+/// Why a field was turned away. Serialises to a snake_case code the client can
+/// show its own message for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldError {
+    Required,
+    TooShort,
+    TooLong,
+    InvalidEmail,
+    InvalidCharacters,
+    MissingLowercase,
+    MissingUppercase,
+    MissingDigit,
+    MissingSymbol,
+    /// On the policy's deny list.
+    Common,
+    /// Contains the account's email.
+    SimilarToEmail,
+    /// In the policy's list of breached passwords.
+    Breached,
+}
+
+/// Everything that's wrong with the input, by field. Responds as a 422 with the
+/// codes as JSON:
+/// ```json
+/// { "errors": { "name": ["invalid_email"], "password": ["too_short"] } }
+/// ```
+/// Build one up with the `check_*` methods from any route that takes credentials:
+/// ```
+/// use rust_authentication::{config::PasswordPolicy, validation::{FieldError, ValidationErrors}};
+///
+/// let errors = ValidationErrors::new()
+///     .check_email("email", "not an email")
+///     .check_password("password", "hunter2", &PasswordPolicy::default(), None);
+/// assert_eq!(errors.get("email"), [FieldError::InvalidCharacters, FieldError::InvalidEmail]);
+/// assert_eq!(errors.get("password"), [FieldError::TooShort]);
+/// assert!(errors.into_result().is_err());
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ValidationErrors {
+    errors: BTreeMap<&'static str, Vec<FieldError>>,
+}
+
+impl ValidationErrors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, field: &'static str, error: FieldError) -> &mut Self {
+        self.errors.entry(field).or_default().push(error);
+        self
+    }
+
+    /// The errors of `field`, empty if it's fine.
+    pub fn get(&self, field: &str) -> &[FieldError] {
+        self.errors.get(field).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// `Ok` if nothing was added.
+    pub fn into_result(self) -> Result<(), Self> {
+        match self.is_empty() {
+            true => Ok(()),
+            false => Err(self),
+        }
+    }
+
+    /// Something that email can be sent to. Surrounding whitespace is ignored,
+    /// as it gets trimmed before being stored.
+    pub fn check_email(mut self, field: &'static str, email: &str) -> Self {
+        let email = email.trim();
+        if email.is_empty() {
+            self.add(field, FieldError::Required);
+            return self;
+        }
+        if email.len() > MAX_EMAIL_LENGTH {
+            self.add(field, FieldError::TooLong);
+        }
+        if email.chars().any(|c| c.is_control() || c.is_whitespace()) {
+            self.add(field, FieldError::InvalidCharacters);
+        }
+        if !is_email(email) {
+            self.add(field, FieldError::InvalidEmail);
+        }
+        self
+    }
+
+    /// Everything in the `policy` apart from the breached list, see [`password::violations`].
+    pub fn check_password(mut self, field: &'static str, password: &str, policy: &PasswordPolicy, email: Option<&str>) -> Self {
+        for error in password::violations(policy, password, email) {
+            self.add(field, error);
+        }
+        self
+    }
+
+    /// Look the password up in the `policy`'s breached list, if it has one. Skipped
+    /// when the field already has errors, as that's the slow part. If the list can't
+    /// be read the password is let through, and the error logged.
+    pub async fn check_breached(mut self, field: &'static str, password: &str, policy: &PasswordPolicy) -> Self {
+        let Some(list) = policy.breached_list.clone() else {
+            return self;
+        };
+        if !self.get(field).is_empty() {
+            return self;
+        }
+
+        let password = password.to_owned();
+        match spawn_blocking(move || password::is_breached(list.as_ref(), &password)).await {
+            Ok(Ok(true)) => {
+                self.add(field, FieldError::Breached);
+            },
+            Ok(Ok(false)) => {},
+            Ok(Err(e)) => error!("Failed to read the breached password list: {}", e),
+            Err(e) => error!("Breached password lookup was interrupted: {}", e),
+        }
+        self
+    }
+}
+
+impl std::fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let fields = self.errors.keys().copied().collect::<Vec<_>>();
+        write!(f, "Invalid input for: {}", fields.join(", "))
+    }
+}
+
+impl std::error::Error for ValidationErrors {}
+
+impl<'r> Responder<'r, 'static> for ValidationErrors {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        (Status::UnprocessableEntity, Json(self)).respond_to(request)
+    }
+}
+
+/// `local@domain`, where the domain is a valid (international) domain name with
+/// at least two labels. Quoted local parts aren't supported, nobody uses them.
+fn is_email(email: &str) -> bool {
+    let Some((local, domain)) = email.rsplit_once('@') else {
+        return false;
+    };
+    if local.is_empty() || local.len() > 64 || local.starts_with('.') || local.ends_with('.') || local.contains("..") {
+        return false;
+    }
+    if local.chars().any(|c| "@\"(),:;<>[\\]".contains(c)) {
+        return false;
+    }
+    match idna::domain_to_ascii(domain) {
+        Ok(ascii) => {
+            let labels = ascii.split('.').collect::<Vec<_>>();
+            labels.len() >= 2 && labels.iter().all(|label| {
+                !label.is_empty()
+                    && label.len() <= 63
+                    && !label.starts_with('-')
+                    && !label.ends_with('-')
+                    && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            })
+        },
+        Err(_) => false,
+    }
+}